@@ -1,83 +1,190 @@
 use alloc::alloc::Layout;
 use core::alloc::GlobalAlloc;
-use super::linked_list::LinkedList;
+use super::linked_list::DoublyLinkedList;
+use core::ptr::{copy_nonoverlapping, null_mut};
+use super::oom::{OomHandler, OomHook};
 use core::cmp::{max, min};
 use spin::SpinLock;
 
 const BUDDY_LEVEL_COUNT: usize = 32;
+// largest block a buddy allocator can hand out
+pub const MAX_BLOCK_SIZE: usize = 1 << (BUDDY_LEVEL_COUNT - 1);
+// add_space may be called this many times
+const MAX_REGIONS: usize = 32;
 
 pub struct BuddyAllocator {
-    pub inner: SpinLock<BuddyAllocatorInner>
+    pub inner: SpinLock<BuddyAllocatorInner>,
+    oom: OomHook
 }
 
 
 impl BuddyAllocator {
     pub const fn empty(unit: usize) -> Self {
+        Self::with_oom_handler(unit, None)
+    }
+    pub const fn with_oom_handler(unit: usize, handler: Option<OomHandler>) -> Self {
         Self {
-            inner: SpinLock::new(BuddyAllocatorInner::empty(unit))
+            inner: SpinLock::new(BuddyAllocatorInner::empty(unit)),
+            oom: OomHook::new(handler)
         }
     }
-    pub unsafe fn add_space(&self, start: usize, end: usize) {
-        self.inner.lock().add_space(start, end);
+    /// # Safety
+    ///
+    /// [start, end) must be memory owned by nobody else for as long as the allocator is used
+    pub unsafe fn add_space(&self, start: usize, end: usize) -> bool {
+        self.inner.lock().add_space(start, end)
+    }
+    pub fn set_oom_handler(&self, handler: Option<OomHandler>) {
+        self.oom.set(handler);
     }
 }
 
 unsafe impl GlobalAlloc for BuddyAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        self.inner.lock().alloc(layout)
+        self.oom.alloc_or_reclaim(&layout, || self.inner.lock().try_alloc(layout))
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         self.inner.lock().dealloc(ptr, layout)
     }  
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if self.inner.lock().realloc_in_place(ptr, layout, new_size) {
+            return ptr;
+        }
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+        if !new_ptr.is_null() {
+            copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
 }
 
 pub struct BuddyAllocatorInner {
     // free_blocks[i] is a linked list of free blocks of size 2^i bytes
-    free_blocks: [LinkedList; BUDDY_LEVEL_COUNT],
+    free_blocks: [DoublyLinkedList; BUDDY_LEVEL_COUNT],
+    // spaces added so far, each with the bitmap of its free blocks
+    regions: [Region; MAX_REGIONS],
+    region_count: usize,
     // minimum unit for allocation is 2^unit bytes
     unit: usize
 }
 
+// a space given to add_space, its bitmap sits at the start of the space
+#[derive(Clone, Copy)]
+struct Region {
+    start: usize,
+    end: usize,
+    // for every level, bit j is set if a free block starts at start + j * 2^level
+    bitmap: *mut u64,
+}
+
+impl Region {
+    const fn empty() -> Self {
+        Self {
+            start: 0,
+            end: 0,
+            bitmap: null_mut(),
+        }
+    }
+
+    // bits of all levels from unit up for a space of len bytes
+    fn bit_count(len: usize, unit: usize) -> usize {
+        (unit..BUDDY_LEVEL_COUNT).map(|i| (len >> i) + 1).sum()
+    }
+
+    fn contains(&self, addr: usize, size: usize) -> bool {
+        self.start <= addr && addr + size <= self.end
+    }
+
+    fn bit(&self, addr: usize, level: usize, unit: usize) -> (*mut u64, u64) {
+        let index = Self::bit_count(self.end - self.start, unit)
+            - Self::bit_count(self.end - self.start, level)
+            + ((addr - self.start) >> level);
+        unsafe { (self.bitmap.add(index / 64), 1 << (index % 64)) }
+    }
+}
+
 impl BuddyAllocatorInner {
     pub const fn empty(unit: usize) -> Self {
         Self {
-            free_blocks: [LinkedList::new(); BUDDY_LEVEL_COUNT],
-            unit: if unit > 3 {
+            free_blocks: [DoublyLinkedList::new(); BUDDY_LEVEL_COUNT],
+            regions: [Region::empty(); MAX_REGIONS],
+            region_count: 0,
+            // a free block holds two pointers
+            unit: if unit > 4 {
                 unit
             } else {
-                3
+                4
             }
         }
     }
 
-    // caller should ensure that [start, end) is allocatable
-    pub unsafe fn add_space(&mut self, mut start: usize, mut end: usize) {
+    // return false if the space is too small or too many spaces have been added
+    /// # Safety
+    ///
+    /// [start, end) must be memory owned by nobody else for as long as the allocator is used
+    pub unsafe fn add_space(&mut self, mut start: usize, mut end: usize) -> bool {
         let unit_space = (1 << self.unit) as usize;
         start = (start + unit_space - 1) & !(unit_space - 1);
         end &= !(unit_space - 1);
+        if start >= end || self.region_count == MAX_REGIONS {
+            return false;
+        }
+        // reserve and clear the bitmap
+        let words = Region::bit_count(end - start, self.unit).div_ceil(64);
+        let bitmap = start as *mut u64;
+        bitmap.write_bytes(0, words);
+        start = (start + words * 8 + unit_space - 1) & !(unit_space - 1);
+        if start >= end {
+            return false;
+        }
+        let region = Region { start, end, bitmap };
+        self.regions[self.region_count] = region;
+        self.region_count += 1;
         while start < end {
             let i = min((end - start).ilog2(), start.trailing_zeros()) as usize;
-            self.free_blocks[i].push(start as *mut usize);
+            self.push_free(&region, start, i);
             start += 1 << i;
         }
+        true
     }
 
+    /// # Safety
+    ///
+    /// same as add_space for [start, end)
     pub unsafe fn new(start: usize, end: usize, unit: usize) -> Self {
         let mut allocator = Self::empty(unit);
         allocator.add_space(start, end);
         allocator
     }
 
+    // bytes managed in all spaces
+    pub fn space(&self) -> usize {
+        self.regions[..self.region_count].iter().map(|region| region.end - region.start).sum()
+    }
+
+    // return null if no free block is large enough
     pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        self.try_alloc(layout).unwrap_or(null_mut())
+    }
+
+    // return none if no free block is large enough
+    pub fn try_alloc(&mut self, layout: Layout) -> Option<*mut u8> {
         let level = self.calc_level(&layout);
         for i in level..BUDDY_LEVEL_COUNT {
             if !self.free_blocks[i].empty() {
                 self.split(i, level);
-                return self.free_blocks[level].pop().unwrap() as *mut u8;
+                return self.pop_free(level).map(|block| block as *mut u8);
             }
         }
-        panic!("[buddy_allocator] unable to allocate memory for {} bytes", layout.size());
+        None
+    }
+
+    // size of the block serving layout
+    pub fn block_size(&self, layout: &Layout) -> usize {
+        1 << self.calc_level(layout)
     }
 
     pub fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
@@ -85,31 +192,101 @@ impl BuddyAllocatorInner {
         self.merge(ptr, level);
     }
 
+    // resize the block at ptr without moving it
+    // shrinking frees the upper halves, growing takes the upper buddies if they are all free
+    pub fn realloc_in_place(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let new_layout = match Layout::from_size_align(new_size, layout.align()) {
+            Ok(new_layout) => new_layout,
+            Err(_) => return false,
+        };
+        let level = self.calc_level(&layout);
+        let target = self.calc_level(&new_layout);
+        let block = ptr as usize;
+        let region = self.region_of(block);
+        if target <= level {
+            for i in (target..level).rev() {
+                self.push_free(&region, block + (1 << i), i);
+            }
+            return true;
+        }
+        if target >= BUDDY_LEVEL_COUNT || block & ((1 << target) - 1) != 0 {
+            return false;
+        }
+        if !(level..target).all(|i| self.is_free(&region, block + (1 << i), i)) {
+            return false;
+        }
+        for i in level..target {
+            self.take_free(&region, block + (1 << i), i);
+        }
+        true
+    }
+
+    fn region_of(&self, addr: usize) -> Region {
+        *self.regions[..self.region_count]
+            .iter()
+            .find(|region| region.contains(addr, 1))
+            .unwrap()
+    }
+
+    fn push_free(&mut self, region: &Region, block: usize, level: usize) {
+        let (word, mask) = region.bit(block, level, self.unit);
+        unsafe {
+            *word |= mask;
+            self.free_blocks[level].push(block as *mut usize);
+        }
+    }
+
+    fn pop_free(&mut self, level: usize) -> Option<usize> {
+        let block = self.free_blocks[level].pop()? as usize;
+        let (word, mask) = self.region_of(block).bit(block, level, self.unit);
+        unsafe {
+            *word &= !mask;
+        }
+        Some(block)
+    }
+
+    fn is_free(&self, region: &Region, block: usize, level: usize) -> bool {
+        if !region.contains(block, 1 << level) {
+            return false;
+        }
+        let (word, mask) = region.bit(block, level, self.unit);
+        unsafe { *word & mask != 0 }
+    }
+
+    // take block out of free_blocks[level] if it is free there, in O(1)
+    fn take_free(&mut self, region: &Region, block: usize, level: usize) -> bool {
+        if !self.is_free(region, block, level) {
+            return false;
+        }
+        let (word, mask) = region.bit(block, level, self.unit);
+        unsafe {
+            *word &= !mask;
+            self.free_blocks[level].remove(block as *mut usize);
+        }
+        true
+    }
+
     // split from free_blocks[start] to make space for free_blocks[end]
     fn split(&mut self, start: usize, end: usize) {
         for i in (end..start).rev() {
-            let block = self.free_blocks[i+1].pop().unwrap() as usize;
+            let block = self.pop_free(i + 1).unwrap();
+            let region = self.region_of(block);
             let buddy = block + (1 << i);
-            self.free_blocks[i].push(buddy as *mut usize);
-            self.free_blocks[i].push(block as *mut usize);
+            self.push_free(&region, buddy, i);
+            self.push_free(&region, block, i);
         }
     }
 
     // merge from free_blocks[start]
     fn merge(&mut self, ptr: *mut u8, start: usize) {
+        let region = self.region_of(ptr as usize);
         let mut cur = ptr as usize;
-        for i in start..BUDDY_LEVEL_COUNT {
-            let buddy = cur  ^ (1 << i);
-            let goal = self.free_blocks[i].iter()
-                       .find(|it| it.get() as usize == buddy);
-            if let Some(it) = goal {
-                it.pop();
-                cur = min(cur, buddy);
-            } else {
-                self.free_blocks[i].push(cur as *mut usize);
-                break;
-            }
+        let mut level = start;
+        while level + 1 < BUDDY_LEVEL_COUNT && self.take_free(&region, cur ^ (1 << level), level) {
+            cur = min(cur, cur ^ (1 << level));
+            level += 1;
         }
+        self.push_free(&region, cur, level);
     }
 
     fn calc_level(&self, layout: &Layout) -> usize {
@@ -117,3 +294,193 @@ impl BuddyAllocatorInner {
             max(self.unit, layout.align().trailing_zeros() as usize))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use core::ptr::addr_of;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    // fresh host memory aligned to its size, leaked with the allocator
+    fn arena(size: usize) -> usize {
+        let arena = unsafe { alloc::alloc::alloc(Layout::from_size_align(size, size).unwrap()) };
+        assert!(!arena.is_null());
+        arena as usize
+    }
+
+    fn allocator(size: usize, unit: usize) -> BuddyAllocatorInner {
+        let start = arena(size);
+        unsafe { BuddyAllocatorInner::new(start, start + size, unit) }
+    }
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 1).unwrap()
+    }
+
+    // allocate blocks of size until the allocator runs out
+    fn drain(inner: &mut BuddyAllocatorInner, size: usize) -> Vec<*mut u8> {
+        core::iter::from_fn(|| inner.try_alloc(layout(size))).collect()
+    }
+
+    #[test]
+    fn raised_minimum_unit() {
+        // a free block must hold the two list pointers
+        assert_eq!(BuddyAllocatorInner::empty(0).block_size(&layout(1)), 16);
+        assert_eq!(BuddyAllocatorInner::empty(4).block_size(&layout(1)), 16);
+        assert_eq!(BuddyAllocatorInner::empty(6).block_size(&layout(1)), 64);
+        assert_eq!(BuddyAllocatorInner::empty(6).block_size(&layout(65)), 128);
+        let mut inner = allocator(1 << 16, 2);
+        let a = inner.alloc(layout(1)) as usize;
+        let b = inner.alloc(layout(1)) as usize;
+        assert_eq!(a.abs_diff(b) % 16, 0);
+        assert!(a.abs_diff(b) >= 16);
+    }
+
+    #[test]
+    fn free_blocks_coalesce_across_orders() {
+        let mut inner = allocator(1 << 16, 4);
+        let big = 1 << 13;
+        let bigs = drain(&mut inner, big);
+        assert!(!bigs.is_empty());
+        for ptr in bigs.iter() {
+            inner.dealloc(*ptr, layout(big));
+        }
+        let smalls = drain(&mut inner, 16);
+        assert!(smalls.len() >= bigs.len() * (big / 16));
+        // free every other block first, so merges happen across all orders at the end
+        for ptr in smalls.iter().step_by(2).chain(smalls.iter().skip(1).step_by(2)) {
+            inner.dealloc(*ptr, layout(16));
+        }
+        assert_eq!(drain(&mut inner, big).len(), bigs.len());
+    }
+
+    #[test]
+    fn split_blocks_are_buddies() {
+        let mut inner = allocator(1 << 16, 4);
+        let ptr = inner.alloc(layout(1 << 12));
+        let _ = drain(&mut inner, 16);
+        // the only free space left is the block freed below, split in halves
+        inner.dealloc(ptr, layout(1 << 12));
+        let low = inner.alloc(layout(1 << 11));
+        let high = inner.alloc(layout(1 << 11));
+        let mut halves = [low as usize, high as usize];
+        halves.sort();
+        assert_eq!(halves, [ptr as usize, ptr as usize + (1 << 11)]);
+        assert!(inner.try_alloc(layout(16)).is_none());
+    }
+
+    #[test]
+    fn regions_up_to_max() {
+        let mut inner = BuddyAllocatorInner::empty(4);
+        let size = 1 << 12;
+        for _ in 0..MAX_REGIONS {
+            let start = arena(size);
+            assert!(unsafe { inner.add_space(start, start + size) });
+        }
+        let start = arena(size);
+        assert!(!unsafe { inner.add_space(start, start + size) });
+        let space = inner.space();
+        assert!(space > MAX_REGIONS * size / 2 && space <= MAX_REGIONS * size);
+        // blocks never merge across regions, each region is drained and refilled alone
+        let blocks = drain(&mut inner, 16);
+        assert_eq!(blocks.len() * 16, space);
+        for ptr in blocks.iter() {
+            inner.dealloc(*ptr, layout(16));
+        }
+        assert!(inner.try_alloc(layout(size)).is_none());
+        assert_eq!(drain(&mut inner, 16).len(), blocks.len());
+    }
+
+    // a 64-byte block aligned to size, so it can grow in place up to there
+    fn aligned_block(inner: &mut BuddyAllocatorInner, size: usize) -> (*mut u8, Vec<*mut u8>) {
+        let mut blocks = drain(inner, 64);
+        let i = blocks.iter().position(|block| *block as usize & (size - 1) == 0).unwrap();
+        (blocks.swap_remove(i), blocks)
+    }
+
+    #[test]
+    fn realloc_grows_in_place_into_free_buddies() {
+        let mut inner = allocator(1 << 16, 4);
+        let (ptr, others) = aligned_block(&mut inner, 256);
+        for other in others {
+            inner.dealloc(other, layout(64));
+        }
+        assert!(inner.realloc_in_place(ptr, layout(64), 256));
+        // the grown block is taken whole, nothing else can land in it
+        let others = drain(&mut inner, 16);
+        assert!(others.iter().all(|other| !(ptr as usize..ptr as usize + 256).contains(&(*other as usize))));
+    }
+
+    #[test]
+    fn realloc_falls_back_when_buddy_is_used() {
+        let mut inner = allocator(1 << 16, 4);
+        let (ptr, _) = aligned_block(&mut inner, 128);
+        assert!(!inner.realloc_in_place(ptr, layout(64), 128));
+        // freeing the upper buddy makes room
+        inner.dealloc(unsafe { ptr.add(64) }, layout(64));
+        assert!(inner.realloc_in_place(ptr, layout(64), 128));
+        assert!(!inner.realloc_in_place(ptr, layout(128), 256));
+    }
+
+    #[test]
+    fn realloc_shrinks_in_place() {
+        let mut inner = allocator(1 << 16, 4);
+        let ptr = inner.alloc(layout(256));
+        let _ = drain(&mut inner, 16);
+        assert!(inner.realloc_in_place(ptr, layout(256), 64));
+        // the upper halves are the only free space
+        assert_eq!(inner.alloc(layout(64)) as usize, ptr as usize + 64);
+        assert_eq!(inner.alloc(layout(128)) as usize, ptr as usize + 128);
+        assert!(inner.try_alloc(layout(16)).is_none());
+    }
+
+    // the allocators hold raw pointers, so they live in static muts like the kernel heap
+    static mut GROWN: BuddyAllocator = BuddyAllocator::empty(4);
+    static GROW_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn grow(_layout: &Layout) -> bool {
+        GROW_CALLS.fetch_add(1, Ordering::SeqCst);
+        let start = arena(1 << 16);
+        unsafe { (*addr_of!(GROWN)).add_space(start, start + (1 << 16)) }
+    }
+
+    #[test]
+    fn oom_hook_runs_once_before_retrying() {
+        let grown = unsafe { &*addr_of!(GROWN) };
+        grown.set_oom_handler(Some(grow));
+        let ptr = unsafe { grown.alloc(layout(64)) };
+        assert!(!ptr.is_null());
+        assert_eq!(GROW_CALLS.load(Ordering::SeqCst), 1);
+        // served from the added space without asking again
+        let ptr = unsafe { grown.alloc(layout(64)) };
+        assert!(!ptr.is_null());
+        assert_eq!(GROW_CALLS.load(Ordering::SeqCst), 1);
+    }
+
+    static mut REFUSED: BuddyAllocator = BuddyAllocator::empty(4);
+    static REFUSE_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn refuse(_layout: &Layout) -> bool {
+        REFUSE_CALLS.fetch_add(1, Ordering::SeqCst);
+        false
+    }
+
+    #[test]
+    fn oom_hook_giving_up_fails_the_allocation() {
+        let refused = unsafe { &*addr_of!(REFUSED) };
+        refused.set_oom_handler(Some(refuse));
+        assert!(unsafe { refused.alloc(layout(64)) }.is_null());
+        assert_eq!(REFUSE_CALLS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn too_small_space_is_refused() {
+        let mut inner = BuddyAllocatorInner::empty(4);
+        let start = arena(64);
+        // the bitmap alone fills it
+        assert!(!unsafe { inner.add_space(start, start + 16) });
+        assert_eq!(inner.space(), 0);
+        assert!(inner.try_alloc(layout(1)).is_none());
+    }
+}
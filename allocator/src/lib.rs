@@ -1,6 +1,8 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 pub mod linked_list;
 pub mod buddy_allocator;
+pub mod slab_allocator;
+pub mod oom;
 
 extern crate alloc;
@@ -12,11 +12,12 @@ impl LinkedList {
         LinkedList { head: null_mut() }
     }
 
-    pub fn push(&mut self, elem: *mut usize) {
-        unsafe {
-            *elem = self.head as usize;
-            self.head = elem;
-        }
+    /// # Safety
+    ///
+    /// elem must point to a writable word that is not in any list
+    pub unsafe fn push(&mut self, elem: *mut usize) {
+        *elem = self.head as usize;
+        self.head = elem;
     }
 
     pub fn empty(&self) -> bool {
@@ -43,6 +44,12 @@ impl LinkedList {
     }
 }
 
+impl Default for LinkedList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct LinkedListIter {
     prev: *mut usize,
     ptr: *mut usize
@@ -80,3 +87,66 @@ impl Iterator for LinkedListIter {
         }
     }
 }
+
+// a doubly linked list without dynamic memory allocation
+// every node takes two words, the next pointer followed by the prev pointer
+#[derive(Clone, Copy, Debug)]
+pub struct DoublyLinkedList {
+    pub head: *mut usize
+}
+
+impl DoublyLinkedList {
+    pub const fn new() -> Self {
+        DoublyLinkedList { head: null_mut() }
+    }
+
+    /// # Safety
+    ///
+    /// elem must point to two writable words that are not in any list
+    pub unsafe fn push(&mut self, elem: *mut usize) {
+        *elem = self.head as usize;
+        *elem.add(1) = 0;
+        if !self.head.is_null() {
+            *self.head.add(1) = elem as usize;
+        }
+        self.head = elem;
+    }
+
+    pub fn empty(&self) -> bool {
+        self.head.is_null()
+    }
+
+    pub fn pop(&mut self) -> Option<*mut usize> {
+        if self.head.is_null() {
+            None
+        } else {
+            let head = self.head;
+            unsafe {
+                self.remove(head);
+            }
+            Some(head)
+        }
+    }
+
+    /// # Safety
+    ///
+    /// elem must be a node of this list
+    pub unsafe fn remove(&mut self, elem: *mut usize) {
+        let next = *elem as *mut usize;
+        let prev = *elem.add(1) as *mut usize;
+        if prev.is_null() {
+            self.head = next;
+        } else {
+            *prev = next as usize;
+        }
+        if !next.is_null() {
+            *next.add(1) = prev as usize;
+        }
+    }
+}
+
+impl Default for DoublyLinkedList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,38 @@
+// out-of-memory callback shared by the allocators
+use alloc::alloc::Layout;
+use core::ptr::null_mut;
+use spin::SpinLock;
+
+// called without the allocator lock held when layout cannot be served
+// return true after freeing or adding memory so that the allocation is retried
+pub type OomHandler = fn(&Layout) -> bool;
+
+pub struct OomHook {
+    handler: SpinLock<Option<OomHandler>>
+}
+
+impl OomHook {
+    pub const fn new(handler: Option<OomHandler>) -> Self {
+        Self {
+            handler: SpinLock::new(handler)
+        }
+    }
+
+    pub fn set(&self, handler: Option<OomHandler>) {
+        *self.handler.lock() = handler;
+    }
+
+    // run try_alloc until it succeeds or the handler gives up, null on failure
+    pub fn alloc_or_reclaim(&self, layout: &Layout, mut try_alloc: impl FnMut() -> Option<*mut u8>) -> *mut u8 {
+        loop {
+            if let Some(ptr) = try_alloc() {
+                return ptr;
+            }
+            let handler = *self.handler.lock();
+            match handler {
+                Some(handler) if handler(layout) => {}
+                _ => return null_mut(),
+            }
+        }
+    }
+}
@@ -0,0 +1,452 @@
+use alloc::alloc::Layout;
+use core::alloc::GlobalAlloc;
+use core::mem::size_of;
+use core::cmp::min;
+use core::ptr::{copy_nonoverlapping, null_mut};
+use super::buddy_allocator::BuddyAllocatorInner;
+use super::linked_list::LinkedList;
+use super::oom::{OomHandler, OomHook};
+use spin::SpinLock;
+
+// size classes are 2^MIN_CLASS ..= 2^MAX_CLASS bytes, larger requests go to the buddy layer
+const MIN_CLASS: usize = 3;
+const MAX_CLASS: usize = 11;
+const CLASS_COUNT: usize = MAX_CLASS - MIN_CLASS + 1;
+// a slab spans at least this many objects of its class, or MIN_SLAB_BYTES if larger
+// the header takes the place of one object in the classes of 512 bytes and up
+const MIN_SLAB_BYTES: usize = 4096;
+const MIN_SLAB_OBJECTS: usize = 8;
+
+pub struct SlabAllocator {
+    pub inner: SpinLock<SlabAllocatorInner>,
+    oom: OomHook
+}
+
+impl SlabAllocator {
+    pub const fn empty(unit: usize) -> Self {
+        Self::with_oom_handler(unit, None)
+    }
+    pub const fn with_oom_handler(unit: usize, handler: Option<OomHandler>) -> Self {
+        Self {
+            inner: SpinLock::new(SlabAllocatorInner::empty(unit)),
+            oom: OomHook::new(handler)
+        }
+    }
+    /// # Safety
+    ///
+    /// [start, end) must be memory owned by nobody else for as long as the allocator is used
+    pub unsafe fn add_space(&self, start: usize, end: usize) -> bool {
+        self.inner.lock().add_space(start, end)
+    }
+    pub fn set_oom_handler(&self, handler: Option<OomHandler>) {
+        self.oom.set(handler);
+    }
+    pub fn stats(&self) -> SlabStats {
+        self.inner.lock().stats()
+    }
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.oom.alloc_or_reclaim(&layout, || self.inner.lock().try_alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.lock().dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if self.inner.lock().realloc_in_place(ptr, layout, new_size) {
+            return ptr;
+        }
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+        if !new_ptr.is_null() {
+            copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+// header at the start of every slab, objects follow it
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    // free objects of this slab
+    free: LinkedList,
+    used: usize,
+}
+
+// slabs of one size class, only slabs with free objects are linked in partial
+struct SlabCache {
+    size: usize,
+    partial: *mut Slab,
+    slabs: usize,
+    used: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CacheStats {
+    // object size of the class
+    pub size: usize,
+    pub slabs: usize,
+    // objects handed out and objects available in all slabs
+    pub used: usize,
+    pub capacity: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct SlabStats {
+    pub caches: [CacheStats; CLASS_COUNT],
+    // requests too large for any class, served by the buddy layer
+    pub large_allocs: usize,
+    pub large_bytes: usize,
+}
+
+impl SlabCache {
+    const fn new(class: usize) -> Self {
+        Self {
+            size: 1 << class,
+            partial: null_mut(),
+            slabs: 0,
+            used: 0,
+        }
+    }
+
+    const fn slab_bytes(&self) -> usize {
+        if self.size * MIN_SLAB_OBJECTS > MIN_SLAB_BYTES {
+            self.size * MIN_SLAB_OBJECTS
+        } else {
+            MIN_SLAB_BYTES
+        }
+    }
+
+    // objects start at the first multiple of size after the header
+    fn first_object(&self) -> usize {
+        (size_of::<Slab>() + self.size - 1) & !(self.size - 1)
+    }
+
+    fn objects_per_slab(&self) -> usize {
+        (self.slab_bytes() - self.first_object()) / self.size
+    }
+
+    fn slab_layout(&self) -> Layout {
+        Layout::from_size_align(self.slab_bytes(), self.slab_bytes()).unwrap()
+    }
+
+    // slabs are aligned to their size, so the header is found by masking
+    fn slab_of(&self, ptr: *mut u8) -> *mut Slab {
+        (ptr as usize & !(self.slab_bytes() - 1)) as *mut Slab
+    }
+
+    unsafe fn link(&mut self, slab: *mut Slab) {
+        (*slab).prev = null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn unlink(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.partial = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+    }
+
+    // carve a new slab out of the buddy layer
+    unsafe fn grow(&mut self, buddy: &mut BuddyAllocatorInner) -> Option<()> {
+        let slab = buddy.try_alloc(self.slab_layout())? as *mut Slab;
+        let mut free = LinkedList::new();
+        let start = slab as usize + self.first_object();
+        for i in (0..self.objects_per_slab()).rev() {
+            free.push((start + i * self.size) as *mut usize);
+        }
+        slab.write(Slab {
+            prev: null_mut(),
+            next: null_mut(),
+            free,
+            used: 0,
+        });
+        self.link(slab);
+        self.slabs += 1;
+        Some(())
+    }
+
+    unsafe fn alloc(&mut self, buddy: &mut BuddyAllocatorInner) -> Option<*mut u8> {
+        if self.partial.is_null() {
+            self.grow(buddy)?;
+        }
+        let slab = self.partial;
+        let object = (*slab).free.pop().unwrap();
+        (*slab).used += 1;
+        if (*slab).free.empty() {
+            self.unlink(slab);
+        }
+        self.used += 1;
+        Some(object as *mut u8)
+    }
+
+    unsafe fn dealloc(&mut self, buddy: &mut BuddyAllocatorInner, ptr: *mut u8) {
+        let slab = self.slab_of(ptr);
+        if (*slab).free.empty() {
+            // the slab was full and gets a free object again
+            self.link(slab);
+        }
+        (*slab).free.push(ptr as *mut usize);
+        (*slab).used -= 1;
+        self.used -= 1;
+        if (*slab).used == 0 {
+            // empty slabs go back to the buddy layer
+            self.unlink(slab);
+            self.slabs -= 1;
+            buddy.dealloc(slab as *mut u8, self.slab_layout());
+        }
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            size: self.size,
+            slabs: self.slabs,
+            used: self.used,
+            capacity: self.slabs * self.objects_per_slab(),
+        }
+    }
+}
+
+pub struct SlabAllocatorInner {
+    // backs slabs and large requests
+    buddy: BuddyAllocatorInner,
+    // caches[i] serves objects of 2^(MIN_CLASS + i) bytes
+    caches: [SlabCache; CLASS_COUNT],
+    large_allocs: usize,
+    large_bytes: usize,
+}
+
+impl SlabAllocatorInner {
+    pub const fn empty(unit: usize) -> Self {
+        Self {
+            buddy: BuddyAllocatorInner::empty(unit),
+            caches: [
+                SlabCache::new(3),
+                SlabCache::new(4),
+                SlabCache::new(5),
+                SlabCache::new(6),
+                SlabCache::new(7),
+                SlabCache::new(8),
+                SlabCache::new(9),
+                SlabCache::new(10),
+                SlabCache::new(11),
+            ],
+            large_allocs: 0,
+            large_bytes: 0,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// [start, end) must be memory owned by nobody else for as long as the allocator is used
+    pub unsafe fn add_space(&mut self, start: usize, end: usize) -> bool {
+        self.buddy.add_space(start, end)
+    }
+
+    // return null if neither a slab nor the buddy layer has space
+    pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        self.try_alloc(layout).unwrap_or(null_mut())
+    }
+
+    // return none if neither a slab nor the buddy layer has space
+    pub fn try_alloc(&mut self, layout: Layout) -> Option<*mut u8> {
+        match Self::class(&layout) {
+            Some(class) => unsafe { self.caches[class].alloc(&mut self.buddy) },
+            None => {
+                let ptr = self.buddy.try_alloc(layout)?;
+                self.large_allocs += 1;
+                self.large_bytes += self.buddy.block_size(&layout);
+                Some(ptr)
+            }
+        }
+    }
+
+    /// # Safety
+    ///
+    /// ptr must have been returned by this allocator for layout and not freed since
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        match Self::class(&layout) {
+            Some(class) => self.caches[class].dealloc(&mut self.buddy, ptr),
+            None => {
+                self.large_allocs -= 1;
+                self.large_bytes -= self.buddy.block_size(&layout);
+                self.buddy.dealloc(ptr, layout);
+            }
+        }
+    }
+
+    // objects staying in their size class and large blocks the buddy layer can resize keep their place
+    pub fn realloc_in_place(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let new_layout = match Layout::from_size_align(new_size, layout.align()) {
+            Ok(new_layout) => new_layout,
+            Err(_) => return false,
+        };
+        match (Self::class(&layout), Self::class(&new_layout)) {
+            (Some(class), Some(new_class)) => class == new_class,
+            (None, None) => {
+                let size = self.buddy.block_size(&layout);
+                if !self.buddy.realloc_in_place(ptr, layout, new_size) {
+                    return false;
+                }
+                self.large_bytes = self.large_bytes - size + self.buddy.block_size(&new_layout);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let mut caches = [self.caches[0].stats(); CLASS_COUNT];
+        for (stats, cache) in caches.iter_mut().zip(self.caches.iter()) {
+            *stats = cache.stats();
+        }
+        SlabStats {
+            caches,
+            large_allocs: self.large_allocs,
+            large_bytes: self.large_bytes,
+        }
+    }
+
+    // index of the cache serving layout, none if it is too large for slabs
+    fn class(layout: &Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align()).next_power_of_two();
+        let class = (size.trailing_zeros() as usize).max(MIN_CLASS);
+        if class <= MAX_CLASS {
+            Some(class - MIN_CLASS)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    const ARENA_SIZE: usize = 1 << 20;
+
+    // an allocator over a fresh arena, the arena is leaked with the allocator
+    fn allocator() -> SlabAllocatorInner {
+        let arena = unsafe { alloc::alloc::alloc(Layout::from_size_align(ARENA_SIZE, ARENA_SIZE).unwrap()) };
+        assert!(!arena.is_null());
+        let mut inner = SlabAllocatorInner::empty(4);
+        assert!(unsafe { inner.add_space(arena as usize, arena as usize + ARENA_SIZE) });
+        inner
+    }
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    #[test]
+    fn size_classes() {
+        assert_eq!(SlabAllocatorInner::class(&layout(1, 1)), Some(0));
+        assert_eq!(SlabAllocatorInner::class(&layout(8, 8)), Some(0));
+        assert_eq!(SlabAllocatorInner::class(&layout(9, 1)), Some(1));
+        // alignment larger than size picks the class of the alignment
+        assert_eq!(SlabAllocatorInner::class(&layout(8, 64)), Some(3));
+        assert_eq!(SlabAllocatorInner::class(&layout(1 << MAX_CLASS, 8)), Some(CLASS_COUNT - 1));
+        assert_eq!(SlabAllocatorInner::class(&layout((1 << MAX_CLASS) + 1, 8)), None);
+    }
+
+    #[test]
+    fn objects_are_aligned_and_distinct() {
+        let mut inner = allocator();
+        let mut ptrs = Vec::new();
+        for _ in 0..100 {
+            let ptr = inner.alloc(layout(24, 8));
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 32, 0);
+            ptrs.push(ptr as usize);
+        }
+        ptrs.sort();
+        ptrs.dedup();
+        assert_eq!(ptrs.len(), 100);
+    }
+
+    #[test]
+    fn slab_capacity() {
+        let mut inner = allocator();
+        for (class, size) in [(0, 8), (6, 512), (8, 2048)] {
+            let ptr = inner.alloc(layout(size, 8));
+            let capacity = inner.stats().caches[class].capacity;
+            // the header takes one object's place once a slab is MIN_SLAB_OBJECTS objects
+            if size * MIN_SLAB_OBJECTS >= MIN_SLAB_BYTES {
+                assert_eq!(capacity, MIN_SLAB_OBJECTS - 1);
+            } else {
+                assert!(capacity >= MIN_SLAB_OBJECTS);
+            }
+            unsafe { inner.dealloc(ptr, layout(size, 8)) };
+        }
+    }
+
+    #[test]
+    fn empty_slabs_are_released() {
+        let mut inner = allocator();
+        let big = layout(ARENA_SIZE / 4, 8);
+        let ptr = inner.alloc(big);
+        assert!(!ptr.is_null());
+        unsafe { inner.dealloc(ptr, big) };
+        // enough objects to need several slabs
+        let small = layout(64, 8);
+        let ptrs: Vec<*mut u8> = (0..1000).map(|_| inner.alloc(small)).collect();
+        assert!(ptrs.iter().all(|ptr| !ptr.is_null()));
+        assert!(inner.stats().caches[3].slabs > 1);
+        for ptr in ptrs {
+            unsafe { inner.dealloc(ptr, small) };
+        }
+        assert_eq!(inner.stats().caches[3].slabs, 0);
+        // the slabs went back to the buddy layer and merged again
+        let ptr = inner.alloc(big);
+        assert!(!ptr.is_null());
+        unsafe { inner.dealloc(ptr, big) };
+    }
+
+    #[test]
+    fn stats() {
+        let mut inner = allocator();
+        let small = layout(100, 8);
+        let large = layout(4096, 8);
+        let a = inner.alloc(small);
+        let b = inner.alloc(small);
+        let c = inner.alloc(large);
+        let stats = inner.stats();
+        let cache = stats.caches[4];
+        assert_eq!(cache.size, 128);
+        assert_eq!(cache.slabs, 1);
+        assert_eq!(cache.used, 2);
+        assert!(cache.capacity >= MIN_SLAB_OBJECTS);
+        assert_eq!(stats.large_allocs, 1);
+        assert_eq!(stats.large_bytes, 4096);
+        unsafe {
+            inner.dealloc(a, small);
+            inner.dealloc(b, small);
+            inner.dealloc(c, large);
+        }
+        let stats = inner.stats();
+        assert_eq!(stats.caches[4].used, 0);
+        assert_eq!(stats.large_allocs, 0);
+        assert_eq!(stats.large_bytes, 0);
+    }
+
+    #[test]
+    fn realloc_within_class_stays_in_place() {
+        let mut inner = allocator();
+        let ptr = inner.alloc(layout(20, 8));
+        assert!(inner.realloc_in_place(ptr, layout(20, 8), 32));
+        assert!(!inner.realloc_in_place(ptr, layout(32, 8), 33));
+    }
+}
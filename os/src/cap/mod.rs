@@ -0,0 +1,132 @@
+use alloc::{sync::{Arc, Weak}, vec::Vec};
+use bitflags::*;
+use crate::{ipc::{Endpoint, Reply}, mm::{frame_allocator::FrameTracker, page_table::PTEFlags}, task::task::TaskControlBlock};
+
+// handle value meaning "no capability"
+pub const NO_CAP: usize = usize::MAX;
+
+bitflags! {
+    pub struct CapRights: usize {
+        const SEND = 1 << 0;
+        const RECV = 1 << 1;
+        const GRANT = 1 << 2;
+    }
+}
+
+// pages shared out of an address space, mapped without copying
+pub struct MemoryObject {
+    pub frames: Vec<Arc<FrameTracker>>,
+    pub perm: PTEFlags,
+}
+
+#[derive(Clone)]
+pub enum CapObject {
+    Endpoint(Arc<Endpoint>),
+    Task(Weak<TaskControlBlock>),
+    Memory(Arc<MemoryObject>),
+    // token to answer one caller
+    Reply(Arc<Reply>),
+}
+
+#[derive(Clone)]
+pub struct Capability {
+    pub object: CapObject,
+    pub rights: CapRights,
+}
+
+impl Capability {
+    pub fn new(object: CapObject, rights: CapRights) -> Self {
+        Self { object, rights }
+    }
+
+    // the endpoint behind this capability if it allows rights
+    pub fn endpoint(&self, rights: CapRights) -> Option<Arc<Endpoint>> {
+        match &self.object {
+            CapObject::Endpoint(endpoint) if self.rights.contains(rights) => Some(endpoint.clone()),
+            _ => None,
+        }
+    }
+
+    // the endpoint this capability may receive on
+    fn receiving(&self) -> Option<&Arc<Endpoint>> {
+        match &self.object {
+            CapObject::Endpoint(endpoint) if self.rights.contains(CapRights::RECV) => Some(endpoint),
+            _ => None,
+        }
+    }
+
+    // a copy with rights restricted to rights
+    pub fn derive(&self, rights: CapRights) -> Self {
+        Self {
+            object: self.object.clone(),
+            rights: self.rights & rights,
+        }
+    }
+}
+
+// per-task table of capabilities, indexed by handle
+pub struct CapTable {
+    slots: Vec<Option<Capability>>,
+}
+
+impl CapTable {
+    pub fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+        }
+    }
+
+    // put cap into the first free slot and return its handle
+    pub fn insert(&mut self, cap: Capability) -> usize {
+        if let Some(endpoint) = cap.receiving() {
+            endpoint.add_receiver();
+        }
+        if let Some(handle) = self.slots.iter().position(|slot| slot.is_none()) {
+            self.slots[handle] = Some(cap);
+            handle
+        } else {
+            self.slots.push(Some(cap));
+            self.slots.len() - 1
+        }
+    }
+
+    pub fn get(&self, handle: usize) -> Option<&Capability> {
+        self.slots.get(handle)?.as_ref()
+    }
+
+    pub fn remove(&mut self, handle: usize) -> Option<Capability> {
+        let cap = self.slots.get_mut(handle)?.take()?;
+        if let Some(endpoint) = cap.receiving() {
+            endpoint.remove_receiver();
+        }
+        Some(cap)
+    }
+
+    // drop every capability
+    pub fn clear(&mut self) {
+        for handle in 0..self.slots.len() {
+            self.remove(handle);
+        }
+        self.slots.clear();
+    }
+
+    // table for a forked child: every handle is inherited at the same index,
+    // but receive rights and reply tokens stay with the parent
+    pub fn fork(&self) -> Self {
+        Self {
+            slots: self.slots
+                .iter()
+                .map(|slot| match slot {
+                    Some(Capability { object: CapObject::Reply(_), .. }) => None,
+                    _ => slot.as_ref().map(|cap| cap.derive(!CapRights::RECV)),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Drop for CapTable {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
@@ -1,6 +1,9 @@
 pub const PAGE_SIZE: usize = 0x1000;
 pub const PAGE_SIZE_BITS: usize = 0xc;
 pub const MEMORY_END: usize = 0x88_000_000;
+// harts with larger ids stay parked, entry.asm reserves a boot stack for each
+pub const MAX_HARTS: usize = 4;
+pub const BOOT_STACK_SIZE: usize = 4096 * 16;
 
 pub const UART_BASE: usize = 0x10000000;
 pub const UART_SIZE: usize = 0x6;
@@ -8,11 +11,34 @@ pub const VIRT_TEST: usize = 0x100000;
 
 pub const MTIME: usize = 0x0200bff8;
 pub const MTIMECMP: usize = 0x02004000;
+// software interrupt pending word of hart i is at CLINT_MSIP + 4 * i
+pub const CLINT_MSIP: usize = 0x02000000;
 pub const CLOCK_FREQ: usize = 12500000;
 pub const TIME_INTERVAL : usize = 1000000;
 
+// user addresses live in the lower half of SV39
+pub const USER_SPACE_END: usize = 0x40_0000_0000;
+// user stack sits at the top of user space
+pub const USER_STACK_TOP: usize = USER_SPACE_END;
+// mmap places memory from here when no address is given
+pub const MMAP_BASE: usize = 0x20_0000_0000;
+
 pub const TRAMPOLINE_ADDR : usize = usize::MAX - PAGE_SIZE + 1;
 pub const TRAP_CONTEXT : usize = TRAMPOLINE_ADDR - PAGE_SIZE;
 pub const USER_STACK_SIZE : usize = 4096 * 2;
+// user stacks grow on demand up to this size, above a guard gap
+pub const USER_STACK_LIMIT: usize = 0x80_0000;
+pub const USER_STACK_GUARD: usize = 4096 * 16;
+// threads other than the main one take the TRAP_CONTEXT slots below it
+pub const MAX_THREADS: usize = 64;
+pub const THREAD_STACK_SIZE: usize = 4096 * 16;
+// a power of two number of pages
 pub const KERNEL_STACK_SIZE : usize = 4096 * 2;
 
+pub const ENDPOINT_QUEUE_SIZE: usize = 32;
+// longest ipc message in words, longer ones are refused before anything is copied
+pub const IPC_MAX_WORDS: usize = 512;
+// longest app name exec accepts
+pub const APP_NAME_MAX: usize = 256;
+// kernel calls to services give up after 1s
+pub const RPC_TIMEOUT: usize = CLOCK_FREQ;
@@ -1,12 +1,25 @@
     .section .text.entry
     .globl _start
 _start:
-    la sp, boot_stack_top
+    # park harts without a boot stack, the limit is MAX_HARTS
+    csrr t0, mhartid
+    li t1, {MAX_HARTS}
+    bgeu t0, t1, park
+    # hart i runs on the i-th boot stack and keeps its id in tp
+    mv tp, t0
+    addi t0, t0, 1
+    li t1, {BOOT_STACK_SIZE}
+    mul t0, t0, t1
+    la sp, boot_stack_bottom
+    add sp, sp, t0
     call rust_start
+park:
+    wfi
+    j park
 
     .section .bss.stack
     .globl boot_stack_bottom
 boot_stack_bottom:
-    .space 4096 * 16
+    .space {BOOT_STACK_SIZE} * {MAX_HARTS}
     .globl boot_stack_top
 boot_stack_top:
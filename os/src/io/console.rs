@@ -1,8 +1,12 @@
 use crate::drivers::uart::UART;
 use core::fmt::{self, Write};
-use crate::task::suspend_current_and_run_next;
+use crate::task::{current_killed, suspend_current_and_run_next};
+use spin::SpinLock;
 struct Stdout;
 
+// keeps lines printed by different harts from interleaving
+static PRINT_LOCK: SpinLock<()> = SpinLock::new(());
+
 impl Write for Stdout {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         for c in s.chars() {
@@ -13,6 +17,7 @@ impl Write for Stdout {
 }
 
 pub fn print(args: fmt::Arguments) {
+    let _guard = PRINT_LOCK.lock();
     Stdout.write_fmt(args).unwrap();
 }
 
@@ -31,10 +36,14 @@ macro_rules! println {
 }
 
 // keep polling to get a char from UART
-pub fn getchar() -> u8 {
+// return none if the current thread is killed meanwhile
+pub fn getchar() -> Option<u8> {
     loop {
         if let Some(c) = UART.getc() {
-            return c;
+            return Some(c);
+        }
+        if current_killed() {
+            return None;
         }
         suspend_current_and_run_next();
     }
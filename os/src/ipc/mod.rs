@@ -1,26 +1,281 @@
-use alloc::{sync::Arc, vec::Vec};
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
 use lazy_static::lazy_static;
 use spin::SpinLock;
-use crate::task::task::TaskControlBlock;
+use crate::{cap::Capability, config::ENDPOINT_QUEUE_SIZE, task::{block_current_and_run_next, processor::current_task, task::TaskControlBlock, wake_task}, time::{add_timer, get_time, remove_timer}};
 
+#[derive(Debug)]
+pub enum IpcError {
+    QueueFull,
+    // the deadline passed before the service answered
+    Timeout,
+    // no task can receive on the endpoint or answer the call any more
+    Closed,
+    // the waiting thread is killed as its process exits
+    Killed,
+    // the message is longer than IPC_MAX_WORDS
+    TooLong,
+}
+
+impl IpcError {
+    // value returned to userspace
+    pub fn code(&self) -> isize {
+        match self {
+            IpcError::QueueFull => -2,
+            IpcError::Timeout => -3,
+            IpcError::Closed => -4,
+            IpcError::Killed => -5,
+            IpcError::TooLong => -6,
+        }
+    }
+}
+
+// a message on its way to a service
 pub struct RpcBuffer {
-    pub caller: Option<Arc<TaskControlBlock>>,
-    pub callee: Option<Arc<TaskControlBlock>>,
     pub sender: usize,
-    pub data: Vec<usize>,  
+    pub data: Vec<usize>,
+    // capability transferred along with data
+    pub cap: Option<Capability>,
+    // where to answer, none for asynchronous messages
+    pub reply: Option<Arc<Reply>>,
+}
+
+// one-shot right to answer a blocked caller
+// the caller fails with Closed if it is dropped without an answer
+pub struct Reply {
+    caller: SpinLock<Option<Arc<TaskControlBlock>>>,
+}
+
+impl Reply {
+    fn new(caller: Arc<TaskControlBlock>) -> Self {
+        Self {
+            caller: SpinLock::new(Some(caller)),
+        }
+    }
+
+    // wake the caller with message, false if it no longer waits
+    // rpc_reply is set before the caller lock is released, so once cancel
+    // returns the answer is either in rpc_reply or never comes
+    pub fn send(&self, message: RpcBuffer) -> bool {
+        let mut caller = self.caller.lock();
+        if let Some(task) = caller.take() {
+            task.inner.lock().rpc_reply = Some(Ok(message));
+            drop(caller);
+            wake_task(task);
+            true
+        } else {
+            false
+        }
+    }
+
+    // the caller gave up waiting
+    fn cancel(&self) {
+        self.caller.lock().take();
+    }
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        let mut caller = self.caller.lock();
+        if let Some(task) = caller.take() {
+            task.inner.lock().rpc_reply = Some(Err(IpcError::Closed));
+            drop(caller);
+            wake_task(task);
+        }
+    }
+}
+
+pub struct Endpoint {
+    inner: SpinLock<EndpointInner>,
 }
 
-impl RpcBuffer {
+pub struct EndpointInner {
+    // service tasks blocked in recv, one is woken per message
+    waiting: VecDeque<Arc<TaskControlBlock>>,
+    // requests and messages waiting to be received
+    queue: VecDeque<RpcBuffer>,
+    // number of asynchronous messages in queue
+    pending: usize,
+    // number of capabilities allowing to receive
+    receivers: usize,
+    closed: bool,
+}
+
+impl Endpoint {
     pub fn new() -> Self {
         Self {
-            caller: None,
-            callee: None,
-            sender: 0,
-            data: Vec::new()
+            inner: SpinLock::new(EndpointInner {
+                waiting: VecDeque::new(),
+                queue: VecDeque::new(),
+                pending: 0,
+                receivers: 0,
+                closed: false,
+            }),
         }
     }
+
+    pub fn add_receiver(&self) {
+        self.inner.lock().receivers += 1;
+    }
+
+    // close the endpoint when its last receiver goes away
+    pub fn remove_receiver(&self) {
+        let mut inner = self.inner.lock();
+        inner.receivers -= 1;
+        if inner.receivers == 0 {
+            drop(inner);
+            self.close();
+        }
+    }
+
+    // drop queued messages, their callers fail with Closed
+    pub fn close(&self) {
+        let mut inner = self.inner.lock();
+        inner.closed = true;
+        inner.pending = 0;
+        let queue = core::mem::take(&mut inner.queue);
+        drop(inner);
+        drop(queue);
+    }
+
+    // send args to the service and block until it replies
+    // give up after timeout ticks if it is given
+    pub fn call(&self, args: Vec<usize>, cap: Option<Capability>, timeout: Option<usize>) -> Result<RpcBuffer, IpcError> {
+        let current = current_task().unwrap();
+        // an answer left over from an earlier call must not be taken for this one
+        current.inner.lock().rpc_reply = None;
+        let mut inner = self.inner.lock();
+        if inner.closed {
+            return Err(IpcError::Closed);
+        }
+        let reply = Arc::new(Reply::new(current.clone()));
+        let waiting = Arc::downgrade(&reply);
+        inner.queue.push_back(RpcBuffer {
+            sender: current.taskid.0,
+            data: args,
+            cap,
+            reply: Some(reply),
+        });
+        if let Some(receiver) = inner.waiting.pop_front() {
+            wake_task(receiver);
+        }
+        drop(inner);
+        let deadline = timeout.map(|ticks| get_time().saturating_add(ticks));
+        if let Some(deadline) = deadline {
+            add_timer(deadline, current.clone());
+        }
+        let result = loop {
+            block_current_and_run_next();
+            // now back to current task
+            let result = current.inner.lock().rpc_reply.take();
+            if let Some(result) = result {
+                break result;
+            }
+            let killed = current.inner.lock().killed;
+            if killed || deadline.is_some_and(|deadline| get_time() >= deadline) {
+                // the request may still be queued or held by the service
+                if let Some(reply) = waiting.upgrade() {
+                    reply.cancel();
+                    let mut inner = self.inner.lock();
+                    inner.queue.retain(|request| {
+                        !request.reply.as_ref().is_some_and(|r| Arc::ptr_eq(r, &reply))
+                    });
+                }
+                // the answer may have come in before cancel
+                if let Some(result) = current.inner.lock().rpc_reply.take() {
+                    break result;
+                }
+                break Err(if killed { IpcError::Killed } else { IpcError::Timeout });
+            }
+        };
+        if let Some(deadline) = deadline {
+            remove_timer(deadline, &current);
+        }
+        result
+    }
+
+    // post data to the service without waiting, fail if the queue is full
+    pub fn send_async(&self, sender: usize, data: Vec<usize>, cap: Option<Capability>) -> Result<(), IpcError> {
+        let mut inner = self.inner.lock();
+        if inner.closed {
+            return Err(IpcError::Closed);
+        }
+        if inner.pending >= ENDPOINT_QUEUE_SIZE {
+            return Err(IpcError::QueueFull);
+        }
+        inner.pending += 1;
+        inner.queue.push_back(RpcBuffer {
+            sender,
+            data,
+            cap,
+            reply: None,
+        });
+        if let Some(receiver) = inner.waiting.pop_front() {
+            wake_task(receiver);
+        }
+        Ok(())
+    }
+
+    // take the next request without blocking
+    pub fn try_recv(&self) -> Option<RpcBuffer> {
+        let mut inner = self.inner.lock();
+        let request = inner.queue.pop_front()?;
+        if request.reply.is_none() {
+            inner.pending -= 1;
+        }
+        Some(request)
+    }
+
+    // block until a request arrives
+    // give up after timeout ticks if it is given
+    pub fn recv(&self, timeout: Option<usize>) -> Result<RpcBuffer, IpcError> {
+        let current = current_task().unwrap();
+        let deadline = timeout.map(|ticks| get_time().saturating_add(ticks));
+        if let Some(deadline) = deadline {
+            add_timer(deadline, current.clone());
+        }
+        let result = loop {
+            if let Some(request) = self.try_recv() {
+                break Ok(request);
+            }
+            if current.inner.lock().killed {
+                break Err(IpcError::Killed);
+            }
+            let mut inner = self.inner.lock();
+            if deadline.is_some_and(|deadline| get_time() >= deadline) {
+                break Err(IpcError::Timeout);
+            }
+            // a message may have come in since try_recv
+            if !inner.queue.is_empty() {
+                continue;
+            }
+            if !inner.waiting.iter().any(|task| Arc::ptr_eq(task, &current)) {
+                inner.waiting.push_back(current.clone());
+            }
+            drop(inner);
+            block_current_and_run_next();
+        };
+        self.inner.lock().waiting.retain(|task| !Arc::ptr_eq(task, &current));
+        if let Some(deadline) = deadline {
+            remove_timer(deadline, &current);
+        }
+        result
+    }
 }
 
 lazy_static!{
-    pub static ref RPC_BUFFER: SpinLock<RpcBuffer> = SpinLock::new(RpcBuffer::new());
+    // endpoint served by process_manager
+    pub static ref PROCESS_MANAGER_EP: Arc<Endpoint> = Arc::new(Endpoint::new());
+    // endpoint served by name_server
+    pub static ref NAME_SERVER_EP: Arc<Endpoint> = Arc::new(Endpoint::new());
+}
+
+// call a service from the kernel on behalf of current task
+pub fn rpc_call(endpoint: &Endpoint, args: Vec<usize>, timeout: Option<usize>) -> Result<Vec<usize>, IpcError> {
+    endpoint.call(args, None, timeout).map(|reply| reply.data)
+}
+
+// post a message to a service from the kernel on behalf of current task
+pub fn rpc_send(endpoint: &Endpoint, args: Vec<usize>) -> Result<(), IpcError> {
+    let sender = current_task().unwrap().taskid.0;
+    endpoint.send_async(sender, args, None)
 }
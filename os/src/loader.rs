@@ -64,11 +64,16 @@ pub fn get_app_data_by_name(name: &str) -> Option<&'static [u8]> {
         .map(get_app_data)
 }
 
+///whether the app is a service started by the kernel
+pub fn is_service(name: &str) -> bool {
+    name == "process_manager" || name == "name_server"
+}
+
 ///list all apps
 pub fn list_apps() {
     println!("/**** APPS ****");
     for app in APP_NAMES.iter() {
-        if *app != "process_manager" {
+        if !is_service(app) {
             println!("{}", app);
         }
     }
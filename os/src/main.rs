@@ -2,6 +2,7 @@
 #![no_main]
 #![feature(panic_info_message)]
 #![feature(vec_into_raw_parts)]
+#![feature(asm_const)]
 
 mod lang_items;
 mod config;
@@ -14,23 +15,29 @@ mod task;
 mod loader;
 mod syscall;
 mod ipc;
+mod cap;
 
 extern crate alloc;
 
 use core::arch::{global_asm, asm};
+use core::sync::atomic::{AtomicBool, Ordering};
 use mm::kernel_heap::init_kernel_heap;
 use mm::frame_allocator::init_frame_allocator;
 use drivers::uart::UART;
 use riscv::register::*;
 use time::init_timer;
-use crate::mm::address_space::set_up_page_table;
-use crate::task::processor::run_tasks;
+use crate::mm::{address_space::set_up_page_table, asid::probe_asid_bits};
+use crate::task::processor::{hart_id, run_tasks};
 use crate::task::{add_init, add_service};
 
-global_asm!(include_str!("entry.asm"));
+global_asm!(
+    include_str!("entry.asm"),
+    MAX_HARTS = const config::MAX_HARTS,
+    BOOT_STACK_SIZE = const config::BOOT_STACK_SIZE,
+);
 global_asm!(include_str!("link_app.S"));
 
-// initialize, from M-mode to S-mode
+// initialize, from M-mode to S-mode, on every hart
 #[no_mangle]
 pub unsafe fn rust_start() -> ! {
     mstatus::set_mpp(mstatus::MPP::Supervisor);
@@ -44,17 +51,34 @@ pub unsafe fn rust_start() -> ! {
     asm!("csrw mideleg, {ones}", ones = in(reg) !0);
     sie::set_stimer();
     sie::set_usoft();
+    // software interrupts from other harts reach M-mode and are passed on to S-mode
+    sie::set_ssoft();
+    mie::set_msoft();
 
     // physical memory protection
     pmpaddr0::write(0x3fffffffffffff);
     pmpcfg0::write(0xf);
 
-    init_timer();
+    init_timer(mhartid::read());
+    // tp keeps the hart id across mret
     asm!("mret", options(noreturn));
 }
 
+// set by hart 0 once the kernel is initialized
+static BOOTED: AtomicBool = AtomicBool::new(false);
+
 #[no_mangle]
 extern "C" fn rust_main() -> !{
+    if hart_id() != 0 {
+        while !BOOTED.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+        set_up_page_table();
+        println!("Hart {} started.", hart_id());
+        run_tasks();
+        unreachable!()
+    }
+
     clear_bss();
     UART.init();
     println!("UART initilized.");
@@ -66,11 +90,13 @@ extern "C" fn rust_main() -> !{
     println!("Frame allocator initilized.");
 
     set_up_page_table();
+    probe_asid_bits();
     println!("Kernel page table set up.");
 
     add_service();
     add_init();
     loader::list_apps();
+    BOOTED.store(true, Ordering::Release);
     run_tasks();
     unreachable!()
 }
@@ -1,11 +1,11 @@
 use core::arch::asm;
 
-use alloc::{collections::BTreeMap, vec::Vec};
+use alloc::{collections::{BTreeMap, BTreeSet}, sync::Arc, vec::Vec};
 use lazy_static::*;
 use spin::SpinLock;
 use crate::{config::*, println, task::show_task_frames};
 
-use super::{address::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum}, frame_allocator::{frame_alloc, FrameTracker}, page_table::{self, PTEFlags, PageTable, PageTableEntry}, range::Range};
+use super::{address::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum}, frame_allocator::{frame_alloc, FrameTracker}, page_table::{self, PTEFlags, PageSize, PageTable, PageTableEntry}, range::{Range, Step}};
 
 extern "C" {
     fn stext();
@@ -24,6 +24,11 @@ lazy_static! {
     pub static ref KERNEL_SPACE: SpinLock<AddrSpace> = SpinLock::new(AddrSpace::new_kernel());
 }
 
+// trap context of the thread in slot, slot 0 is the main thread
+pub fn trap_cx_addr(slot: usize) -> usize {
+    TRAP_CONTEXT - slot * PAGE_SIZE
+}
+
 pub fn set_up_page_table() {
     // note that KERNEL_SPACE has been initialized due to lazy_static
     let table = &KERNEL_SPACE.lock().root_table;
@@ -37,27 +42,48 @@ pub fn set_up_page_table() {
 pub struct AddrSpace {
     pub root_table: PageTable,
     areas: Vec<MapArea>,
+    // user heap is [heap_bottom, brk), page-aligned bottom right after the ELF
+    heap_bottom: usize,
+    brk: usize,
+    // pages the main stack may still grow into, from its limit up to its lowest page
+    // kept apart from the areas, which munmap and mprotect may split
+    stack_growth: Range<VirtPageNum>,
+    // user stack bottoms of threads by their TRAP_CONTEXT slot, the main thread has slot 0
+    thread_stacks: BTreeMap<usize, VirtPageNum>,
 }
 
 impl AddrSpace {
-    pub fn new_empty() -> Self {
+    // return none if frames or ASIDs run out
+    pub fn new_empty() -> Option<Self> {
+        Some(Self::with_table(PageTable::new()?))
+    }
+
+    fn with_table(root_table: PageTable) -> Self {
         Self {
-            root_table: PageTable::new(),
+            root_table,
             areas: Vec::new(),
+            heap_bottom: 0,
+            brk: 0,
+            stack_growth: Range::new(
+                VirtAddr(USER_STACK_TOP - USER_STACK_LIMIT).floor(),
+                VirtAddr(USER_STACK_TOP).floor(),
+            ),
+            thread_stacks: BTreeMap::new(),
         }
     }
 
-    fn map_trampoline(&mut self) {
+    fn map_trampoline(&mut self) -> Option<()> {
         self.root_table.map(
             VirtAddr(TRAMPOLINE_ADDR).floor(), 
             PhysAddr(_trampoline as usize).floor(), 
             PTEFlags::R | PTEFlags::X
-        );
+        )
     }
 
-    pub fn push(&mut self, mut area: MapArea, data: Option<&[u8]>) {
+    // return none and leave nothing mapped if frames run out
+    pub fn push(&mut self, mut area: MapArea, data: Option<&[u8]>) -> Option<()> {
         let table = &mut self.root_table;
-        area.map(table);
+        area.map(table)?;
         
         if let Some(data) = data {
             area.copy_from_bytes(data);
@@ -67,6 +93,7 @@ impl AddrSpace {
         self.areas.push(area);
         //println!("AFTER");
         //show_task_frames();
+        Some(())
     }
 
     // remove MapArea starting from start_vpn
@@ -82,9 +109,10 @@ impl AddrSpace {
         }
     }
 
+    // the kernel cannot run without its own mappings, so failures panic here
     pub fn new_kernel() -> Self {
-        let mut ret = Self::new_empty();
-        ret.map_trampoline();
+        let mut ret = Self::with_table(PageTable::new_kernel().unwrap());
+        ret.map_trampoline().unwrap();
 
         // map .text
         ret.push( 
@@ -94,7 +122,7 @@ impl AddrSpace {
                 MapType::Identical,
                 PTEFlags::R | PTEFlags::X),
             None
-        );
+        ).unwrap();
         // map .rodata
         ret.push( 
             MapArea::new(
@@ -103,7 +131,7 @@ impl AddrSpace {
                 MapType::Identical,
                 PTEFlags::R),
             None
-        );
+        ).unwrap();
         // map .data
         ret.push( 
             MapArea::new(
@@ -112,7 +140,7 @@ impl AddrSpace {
                 MapType::Identical,
                 PTEFlags::R | PTEFlags::W),
             None
-        );
+        ).unwrap();
         // map .bss
         ret.push( 
             MapArea::new(
@@ -121,7 +149,7 @@ impl AddrSpace {
                 MapType::Identical,
                 PTEFlags::R | PTEFlags::W),
             None
-        );
+        ).unwrap();
         // map physical memory
         ret.push( 
             MapArea::new(
@@ -130,7 +158,7 @@ impl AddrSpace {
                 MapType::Identical,
                 PTEFlags::R | PTEFlags::W),
             None
-        );
+        ).unwrap();
         // map UART port
         ret.push( 
             MapArea::new(
@@ -139,7 +167,7 @@ impl AddrSpace {
                 MapType::Identical,
                 PTEFlags::R | PTEFlags::W),
             None
-        );
+        ).unwrap();
         // map VIRT_TEST
         ret.push( 
             MapArea::new(
@@ -148,7 +176,7 @@ impl AddrSpace {
                 MapType::Identical,
                 PTEFlags::R | PTEFlags::W),
             None
-        );
+        ).unwrap();
         // map timer port
         ret.push( 
             MapArea::new(
@@ -157,13 +185,23 @@ impl AddrSpace {
                 MapType::Identical,
                 PTEFlags::R | PTEFlags::W),
             None
-        );
+        ).unwrap();
+        // map software interrupt port, used to interrupt other harts
+        ret.push( 
+            MapArea::new(
+                CLINT_MSIP.into(), 
+                (CLINT_MSIP + 4 * MAX_HARTS).into(), 
+                MapType::Identical,
+                PTEFlags::R | PTEFlags::W),
+            None
+        ).unwrap();
         ret
     }
 
-    pub fn new_user(elf_data: &[u8]) -> (Self, usize, usize) {
-        let mut user_space = Self::new_empty();
-        user_space.map_trampoline();
+    // return none if frames run out
+    pub fn new_user(elf_data: &[u8]) -> Option<(Self, usize, usize)> {
+        let mut user_space = Self::new_empty()?;
+        user_space.map_trampoline()?;
         let elf = xmas_elf::ElfFile::new(elf_data).unwrap();
         let elf_header = elf.header;
         let magic = elf_header.pt1.magic;
@@ -186,28 +224,39 @@ impl AddrSpace {
                 if ph_flags.is_execute() {
                     perm |= PTEFlags::X;
                 }
-                let map_area = MapArea::new(start_va, end_va, MapType::Framed, perm);
-                max_end_vpn = map_area.range.end;
-                user_space.push(
-                    map_area,
-                    Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
-                );              
+                // pages holding file data are filled now, the zeroed rest on first access
+                let file_end_va: VirtAddr = ((ph.virtual_addr() + ph.file_size()) as usize).into();
+                if ph.file_size() > 0 {
+                    let map_area = MapArea::new(start_va, file_end_va, MapType::Framed, perm);
+                    user_space.push(
+                        map_area,
+                        Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
+                    )?;
+                }
+                let lazy_start_va = if ph.file_size() > 0 { file_end_va.ceil().to_addr() } else { start_va };
+                let map_area = MapArea::new(lazy_start_va, end_va, MapType::Lazy, perm);
+                max_end_vpn = max_end_vpn.max(map_area.range.end);
+                if map_area.range.start < map_area.range.end {
+                    user_space.push(map_area, None)?;
+                }
             }
         }
-        // map user stack
-        let max_end_va: VirtAddr = max_end_vpn.to_addr();
-        let mut user_stack_bottom: usize = max_end_va.into();
-        user_stack_bottom += PAGE_SIZE; // guard page
-        let user_stack_top = user_stack_bottom + USER_STACK_SIZE;
+        // heap starts empty after the ELF, a guard page apart
+        user_space.heap_bottom = max_end_vpn.to_addr().0 + PAGE_SIZE;
+        user_space.brk = user_space.heap_bottom;
+        // map user stack at the top of user space, it grows on page faults
+        let user_stack_top = USER_STACK_TOP;
+        let user_stack_bottom = user_stack_top - USER_STACK_SIZE;
         user_space.push(
             MapArea::new(
                 user_stack_bottom.into(),
                 user_stack_top.into(),
-                MapType::Framed,
+                MapType::Lazy,
                 PTEFlags::R | PTEFlags::W | PTEFlags::U,
             ),
             None,
-        );
+        )?;
+        user_space.stack_growth.end = VirtAddr(user_stack_bottom).floor();
         // map TrapContext
         user_space.push(
             MapArea::new(
@@ -217,49 +266,405 @@ impl AddrSpace {
                 PTEFlags::R | PTEFlags::W,
             ),
             None,
-        );
-        (
+        )?;
+        Some((
             user_space,
             user_stack_top,
             elf.header.pt2.entry_point() as usize,
-        )
+        ))
+    }
+
+    // called right before hart returns to user mode in the space
+    // it drops stale translations, and harts revoking a mapping interrupt it from now on
+    pub fn activate(&self, hart: usize) {
+        self.root_table.activate_user(hart);
+    }
+
+    // map the trap context and a lazy user stack of a new thread, a guard page is left below the stack
+    // return its slot and stack top, none if slots, address space or frames run out
+    pub fn alloc_thread(&mut self) -> Option<(usize, usize)> {
+        let slot = (1..MAX_THREADS).find(|slot| !self.thread_stacks.contains_key(slot))?;
+        let pages = THREAD_STACK_SIZE / PAGE_SIZE;
+        let guard = self.find_free(pages + 1)?;
+        let bottom = VirtPageNum(guard.0 + 1);
+        let top = VirtPageNum(bottom.0 + pages);
+        self.push(
+            MapArea::new(
+                trap_cx_addr(slot).into(),
+                (trap_cx_addr(slot) + PAGE_SIZE).into(),
+                MapType::Framed,
+                PTEFlags::R | PTEFlags::W,
+            ),
+            None,
+        )?;
+        // lazy areas map nothing, so this cannot run out of memory
+        self.areas.push(MapArea::new(bottom.to_addr(), top.to_addr(), MapType::Lazy, PTEFlags::R | PTEFlags::W | PTEFlags::U));
+        // the guard page is reserved by an area without permissions, so mmap skips it,
+        // munmap refuses it and an overflowing access faults
+        self.areas.push(MapArea::new(guard.to_addr(), bottom.to_addr(), MapType::Lazy, PTEFlags::empty()));
+        self.thread_stacks.insert(slot, bottom);
+        Some((slot, top.to_addr().0))
+    }
+
+    // unmap what alloc_thread mapped for slot
+    pub fn dealloc_thread(&mut self, slot: usize) {
+        if let Some(bottom) = self.thread_stacks.remove(&slot) {
+            self.remove_area(VirtAddr(trap_cx_addr(slot)).floor());
+            self.remove_area(bottom);
+            self.remove_area(VirtPageNum(bottom.0 - 1));
+        }
     }
 
     pub fn translate_vpn(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.root_table.translate_vpn(vpn)
     }
 
-    pub fn from_existed_user(user_space: &AddrSpace) -> AddrSpace {
-        let mut space = Self::new_empty();
-        space.map_trampoline();
-        // copy data sections/trap_context/user_stack
+    // writable user pages are shared copy-on-write, the rest is copied
+    // return none if frames run out
+    pub fn from_existed_user(user_space: &mut AddrSpace) -> Option<AddrSpace> {
+        let mut space = Self::new_empty()?;
+        space.map_trampoline()?;
+        space.heap_bottom = user_space.heap_bottom;
+        space.brk = user_space.brk;
+        space.stack_growth = user_space.stack_growth;
+        // areas of other threads are copied along and freed with the space
+        space.thread_stacks = user_space.thread_stacks.clone();
+        // parent's pages losing write permission are flushed by remap, even if copying fails halfway
+        space.copy_areas(user_space).map(|_| space)
+    }
+
+    fn copy_areas(&mut self, user_space: &mut AddrSpace) -> Option<()> {
         for area in user_space.areas.iter() {
-            let new_area = MapArea::from_another(area);
-            space.push(new_area, None);
-            // copy data from another space
+            let mut new_area = MapArea::from_another(area);
+            if area.map_type == MapType::Shared {
+                // shared pages stay shared with the child
+                new_area.frame_map = area.frame_map.clone();
+                self.push(new_area, None)?;
+                continue;
+            }
+            if area.is_private_user() {
+                let cow_perm = area.perm - PTEFlags::W;
+                // pages never touched stay lazy in both spaces
+                for (vpn, frame) in area.frame_map.iter() {
+                    if area.exported.contains(vpn) {
+                        // shared through a memory capability, the child gets its own copy
+                        let copy = frame_alloc()?;
+                        copy.ppn.get_bytes_array().copy_from_slice(frame.ppn.get_bytes_array());
+                        self.root_table.map(*vpn, copy.ppn, area.perm)?;
+                        new_area.frame_map.insert(*vpn, Arc::new(copy));
+                    } else {
+                        self.root_table.map(*vpn, frame.ppn, cow_perm)?;
+                        user_space.root_table.remap(*vpn, frame.ppn, cow_perm);
+                        new_area.frame_map.insert(*vpn, frame.clone());
+                    }
+                }
+                self.areas.push(new_area);
+                continue;
+            }
+            if area.map_type == MapType::Lazy {
+                // a reserved range such as a thread's guard page, nothing is mapped
+                self.areas.push(new_area);
+                continue;
+            }
+            // trap context is written by the kernel through its frame, copy it
+            self.push(new_area, None)?;
             for vpn in area.range.iter() {
                 let src_ppn = user_space.translate_vpn(vpn).unwrap().ppn();
-                let dst_ppn = space.translate_vpn(vpn).unwrap().ppn();
+                let dst_ppn = self.translate_vpn(vpn).unwrap().ppn();
                 dst_ppn.get_bytes_array().copy_from_slice(src_ppn.get_bytes_array());
             }
         }
-        space
+        Some(())
+    }
+
+    // fill a lazy page on first access and give a copy-on-write page
+    // its write permission back, copying the frame if it is still shared
+    // return false if the access is not allowed
+    pub fn handle_page_fault(&mut self, vpn: VirtPageNum, access: Access) -> bool {
+        let area = match self.areas.iter_mut().find(|area| area.contains(vpn)) {
+            Some(area) => area,
+            None => return false,
+        };
+        if !area.is_private_user() || !area.perm.contains(access.perm()) {
+            return false;
+        }
+        let pte = match self.root_table.translate_vpn(vpn) {
+            Some(pte) if pte.is_valid() => pte,
+            _ => {
+                if area.map_type != MapType::Lazy {
+                    return false;
+                }
+                // out of memory, the faulting task cannot go on
+                return area.populate(&mut self.root_table, vpn).is_some();
+            }
+        };
+        if access != Access::Store || pte.flags().contains(PTEFlags::W) {
+            return true;
+        }
+        let frame = area.frame_map.get_mut(&vpn).unwrap();
+        if Arc::strong_count(frame) > 1 && !area.exported.contains(&vpn) {
+            let copy = match frame_alloc() {
+                Some(copy) => copy,
+                None => return false,
+            };
+            copy.ppn.get_bytes_array().copy_from_slice(frame.ppn.get_bytes_array());
+            *frame = Arc::new(copy);
+        }
+        self.root_table.remap(vpn, frame.ppn, area.perm);
+        true
+    }
+
+    // lowest address the stack may grow to
+    fn stack_limit_addr(&self) -> usize {
+        self.stack_growth.start.to_addr().0
+    }
+
+    // the growth range of the stack and the guard gap below it, no area may be placed there
+    fn stack_reserved(&self) -> Range<VirtPageNum> {
+        Range::new(VirtAddr(self.stack_limit_addr() - USER_STACK_GUARD).floor(), self.stack_growth.end)
+    }
+
+    // extend the stack down to addr if addr is just below the stack pointer
+    // and within the growth range, the new pages are filled on fault as lazy pages
+    pub fn grow_stack(&mut self, addr: usize, sp: usize) {
+        let vpn = VirtAddr(addr).floor();
+        let growth = self.stack_growth;
+        if vpn < growth.start || vpn >= growth.end || addr + PAGE_SIZE < sp {
+            return;
+        }
+        let perm = PTEFlags::R | PTEFlags::W | PTEFlags::U;
+        // the lowest stack pages may have been unmapped or changed by mprotect
+        let stack = self.areas
+            .iter_mut()
+            .find(|area| area.range.start == growth.end && area.map_type == MapType::Lazy && area.perm == perm);
+        if let Some(stack) = stack {
+            stack.range.start = vpn;
+        } else {
+            // lazy areas map nothing, so this cannot run out of memory
+            self.areas.push(MapArea::new(vpn.to_addr(), growth.end.to_addr(), MapType::Lazy, perm));
+        }
+        self.stack_growth.end = vpn;
+    }
+
+    // whether addr falls into the guard gap below the stack limit
+    pub fn is_stack_overflow(&self, addr: usize) -> bool {
+        let limit = self.stack_limit_addr();
+        addr < limit && addr >= limit - USER_STACK_GUARD
+    }
+
+    // frame of user page vpn if current permissions allow the access,
+    // lazy and copy-on-write pages are resolved first like on a page fault
+    pub fn user_page(&mut self, vpn: VirtPageNum, write: bool) -> Option<PhysPageNum> {
+        if vpn >= VirtAddr(USER_SPACE_END).floor() {
+            return None;
+        }
+        self.handle_page_fault(vpn, if write { Access::Store } else { Access::Load });
+        let pte = self.root_table.translate_vpn(vpn)?;
+        let needed = PTEFlags::V | PTEFlags::U | if write { PTEFlags::W } else { PTEFlags::R };
+        if pte.flags().contains(needed) {
+            Some(pte.ppn())
+        } else {
+            None
+        }
+    }
+
+    // frames backing user pages [start, end) and the permission they share
+    pub fn share_frames(&mut self, start: VirtPageNum, end: VirtPageNum) -> Option<(Vec<Arc<FrameTracker>>, PTEFlags)> {
+        let mut frames = Vec::new();
+        let mut perm = PTEFlags::R | PTEFlags::W | PTEFlags::X | PTEFlags::U;
+        for vpn in Range::new(start, end).iter() {
+            // fill a lazy page first
+            self.handle_page_fault(vpn, Access::Load);
+            let area = self.areas.iter_mut().find(|area| area.contains(vpn))?;
+            if !area.perm.contains(PTEFlags::U) {
+                return None;
+            }
+            frames.push(area.export(&mut self.root_table, vpn)?);
+            perm &= area.perm;
+        }
+        Some((frames, perm))
+    }
+
+    // whether [start, end) is inside user space and not used by any area
+    // nor reserved for the stack to grow
+    pub fn is_free(&self, start: VirtPageNum, end: VirtPageNum) -> bool {
+        let reserved = self.stack_reserved();
+        start < end
+            && end <= VirtAddr(USER_SPACE_END).floor()
+            && !(reserved.start < end && start < reserved.end)
+            && self.areas.iter().all(|area| !area.overlaps(start, end))
+    }
+
+    pub fn brk(&self) -> usize {
+        self.brk
+    }
+
+    // move the end of the heap to brk, return false if it cannot be moved there
+    // heap pages are lazy, so growing costs nothing until they are touched
+    pub fn set_brk(&mut self, brk: usize) -> bool {
+        if brk < self.heap_bottom || brk > USER_SPACE_END {
+            return false;
+        }
+        let bottom = VirtAddr(self.heap_bottom).floor();
+        let old_end = VirtAddr(self.brk).ceil();
+        let new_end = VirtAddr(brk).ceil();
+        if new_end > old_end {
+            if !self.is_free(old_end, new_end) {
+                return false;
+            }
+            let heap = self.areas
+                .iter_mut()
+                .find(|area| area.range.start == bottom && area.range.end == old_end && area.map_type == MapType::Lazy);
+            if let Some(heap) = heap {
+                heap.range.end = new_end;
+            } else if old_end == bottom {
+                // lazy areas map nothing, so this cannot run out of memory
+                let heap = MapArea::new(bottom.to_addr(), new_end.to_addr(), MapType::Lazy, PTEFlags::R | PTEFlags::W | PTEFlags::U);
+                self.areas.push(heap);
+            } else {
+                // the heap was cut by munmap
+                return false;
+            }
+        } else if new_end < old_end {
+            self.unmap_range(new_end, old_end);
+        }
+        self.brk = brk;
+        true
+    }
+
+    // lowest free range of pages pages long at or above MMAP_BASE
+    // skipping areas and the range reserved for the stack
+    pub fn find_free(&self, pages: usize) -> Option<VirtPageNum> {
+        let reserved = self.stack_reserved();
+        let mut start = VirtAddr(MMAP_BASE).floor();
+        loop {
+            let end = VirtPageNum(start.0 + pages);
+            if end > VirtAddr(USER_SPACE_END).floor() {
+                return None;
+            }
+            let used = self.areas.iter().map(|area| area.range).chain(Some(reserved));
+            match used.filter(|range| range.start < end && start < range.end).map(|range| range.end).max() {
+                Some(next) => start = next,
+                None => return Some(start),
+            }
+        }
+    }
+
+    // split the area containing vpn so that an area starts at vpn
+    fn split_at(&mut self, vpn: VirtPageNum) {
+        if let Some(i) = self.areas.iter().position(|area| area.range.start < vpn && vpn < area.range.end) {
+            let tail = self.areas[i].split_off(vpn);
+            self.areas.push(tail);
+        }
+    }
+
+    // unmap user pages of [start, end), areas are split if partly covered
+    // return false if the range leaves user space or hits a kernel area
+    pub fn unmap_range(&mut self, start: VirtPageNum, end: VirtPageNum) -> bool {
+        if start >= end || end > VirtAddr(USER_SPACE_END).floor() {
+            return false;
+        }
+        if self.areas.iter().any(|area| area.overlaps(start, end) && !area.perm.contains(PTEFlags::U)) {
+            return false;
+        }
+        self.split_at(start);
+        self.split_at(end);
+        let table = &mut self.root_table;
+        self.areas.retain_mut(|area| {
+            if area.overlaps(start, end) {
+                area.unmap(table);
+                false
+            } else {
+                true
+            }
+        });
+        true
     }
 
+    // change permission of [start, end) to perm, areas are split if partly covered
+    // return false unless every page belongs to a framed or lazy user area
+    pub fn protect_range(&mut self, start: VirtPageNum, end: VirtPageNum, perm: PTEFlags) -> bool {
+        if start >= end {
+            return false;
+        }
+        let mut covered = 0;
+        for area in self.areas.iter().filter(|area| area.overlaps(start, end)) {
+            if !area.is_private_user() {
+                return false;
+            }
+            covered += area.range.end.min(end).0 - area.range.start.max(start).0;
+        }
+        if covered != end.0 - start.0 {
+            return false;
+        }
+        self.split_at(start);
+        self.split_at(end);
+        for area in self.areas.iter_mut().filter(|area| area.overlaps(start, end)) {
+            area.perm = perm;
+            for (vpn, frame) in area.frame_map.iter() {
+                // pages still shared copy-on-write get write permission on fault
+                let pte = self.root_table.translate_vpn(*vpn).unwrap();
+                let shared = Arc::strong_count(frame) > 1 && !area.exported.contains(vpn);
+                let flags = if !pte.flags().contains(PTEFlags::W) && shared {
+                    perm - PTEFlags::W
+                } else {
+                    perm
+                };
+                self.root_table.remap(*vpn, frame.ppn, flags);
+            }
+        }
+        true
+    }
+
+    // remove the shared MapArea starting from start_vpn
+    pub fn remove_shared_area(&mut self, start_vpn: VirtPageNum) -> bool {
+        let shared = self.areas
+            .iter()
+            .any(|area| area.range.start == start_vpn && area.map_type == MapType::Shared);
+        if shared {
+            self.remove_area(start_vpn);
+        }
+        shared
+    }
 }
 
 pub struct MapArea {
     range: Range<VirtPageNum>,
-    frame_map: BTreeMap<VirtPageNum, FrameTracker>,
+    // frames may be shared with other address spaces
+    frame_map: BTreeMap<VirtPageNum, Arc<FrameTracker>>,
+    // private pages shared through a memory capability, never copied on write
+    exported: BTreeSet<VirtPageNum>,
     map_type: MapType,
     perm: PTEFlags,
 }
 
 #[derive(Copy, Clone, PartialEq, Debug)]
-/// map type for MapArea: identical or framed
+/// kind of access that faulted, each needs its own permission on the area
+pub enum Access {
+    Load,
+    Store,
+    Fetch,
+}
+
+impl Access {
+    fn perm(self) -> PTEFlags {
+        match self {
+            Access::Load => PTEFlags::R,
+            Access::Store => PTEFlags::W,
+            Access::Fetch => PTEFlags::X,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+/// map type for MapArea: identical, framed, shared or lazy
+/// a shared area maps frames given at creation and shares them on fork
+/// a lazy area only reserves its range, frames are allocated on first access
 pub enum MapType {
     Identical,
     Framed,
+    Shared,
+    Lazy,
 }
 
 impl MapArea {
@@ -269,52 +674,170 @@ impl MapArea {
         Self {
             range: Range::new(l, r),
             frame_map: BTreeMap::new(),
+            exported: BTreeSet::new(),
             map_type,
             perm
         }
     }
 
+    pub fn new_shared(start: VirtPageNum, frames: Vec<Arc<FrameTracker>>, perm: PTEFlags) -> Self {
+        let mut frame_map = BTreeMap::new();
+        let mut end = start;
+        for frame in frames {
+            frame_map.insert(end, frame);
+            end.step();
+        }
+        Self {
+            range: Range::new(start, end),
+            frame_map,
+            exported: BTreeSet::new(),
+            map_type: MapType::Shared,
+            perm
+        }
+    }
+
     pub fn from_another(another: &MapArea) -> Self {
         Self {
             range: Range::new(another.range.start, another.range.end),
             frame_map: BTreeMap::new(),
+            exported: BTreeSet::new(),
             map_type: another.map_type,
             perm: another.perm,
         }
     }
 
-    pub fn map_one(&mut self, table: &mut PageTable, vpn: VirtPageNum) {
+    pub fn map_one(&mut self, table: &mut PageTable, vpn: VirtPageNum) -> Option<()> {
         let ppn = match self.map_type {
             MapType::Identical => PhysPageNum(vpn.0),
             MapType::Framed => {
-                let frame = frame_alloc();
+                let frame = frame_alloc()?;
                 let res = frame.ppn;
-                self.frame_map.insert(vpn, frame);
+                self.frame_map.insert(vpn, Arc::new(frame));
                 res
             }
+            MapType::Shared => self.frame_map.get(&vpn).unwrap().ppn,
+            MapType::Lazy => return Some(()),
         };
-        table.map(vpn, ppn, self.perm);
+        table.map(vpn, ppn, self.perm)
+    }
+
+    // allocate the frame of a lazy page
+    fn populate(&mut self, table: &mut PageTable, vpn: VirtPageNum) -> Option<()> {
+        let frame = frame_alloc()?;
+        table.map(vpn, frame.ppn, self.perm)?;
+        self.frame_map.insert(vpn, Arc::new(frame));
+        Some(())
     }
 
     pub fn unmap_one(&mut self, table: &mut PageTable, vpn: VirtPageNum) {
-        if self.map_type == MapType::Framed {
-            self.frame_map.remove(&vpn);
+        match self.map_type {
+            MapType::Identical => {}
+            // untouched lazy pages were never mapped
+            MapType::Lazy => if self.frame_map.remove(&vpn).is_none() {
+                return;
+            },
+            _ => {
+                self.frame_map.remove(&vpn);
+            }
         }
+        self.exported.remove(&vpn);
         table.unmap(vpn);
     }
 
-    pub fn map(&mut self, table: &mut PageTable) {
+    // frame of vpn to share through a memory capability, a private page still
+    // shared copy-on-write with a forked task gets its own frame first
+    fn export(&mut self, table: &mut PageTable, vpn: VirtPageNum) -> Option<Arc<FrameTracker>> {
+        let private = self.is_private_user();
+        let frame = self.frame_map.get_mut(&vpn)?;
+        if private && !self.exported.contains(&vpn) {
+            if Arc::strong_count(frame) > 1 {
+                let copy = frame_alloc()?;
+                copy.ppn.get_bytes_array().copy_from_slice(frame.ppn.get_bytes_array());
+                *frame = Arc::new(copy);
+                table.remap(vpn, frame.ppn, self.perm);
+            }
+            self.exported.insert(vpn);
+        }
+        Some(frame.clone())
+    }
+
+    // framed or lazy user pages owned by this address space
+    fn is_private_user(&self) -> bool {
+        matches!(self.map_type, MapType::Framed | MapType::Lazy) && self.perm.contains(PTEFlags::U)
+    }
+
+    pub fn contains(&self, vpn: VirtPageNum) -> bool {
+        self.range.start <= vpn && vpn < self.range.end
+    }
+
+    fn overlaps(&self, start: VirtPageNum, end: VirtPageNum) -> bool {
+        self.range.start < end && start < self.range.end
+    }
+
+    // self keeps [start, vpn) and [vpn, end) is returned as a new area
+    fn split_off(&mut self, vpn: VirtPageNum) -> MapArea {
+        let tail = Self {
+            range: Range::new(vpn, self.range.end),
+            frame_map: self.frame_map.split_off(&vpn),
+            exported: self.exported.split_off(&vpn),
+            map_type: self.map_type,
+            perm: self.perm,
+        };
+        self.range.end = vpn;
+        tail
+    }
+
+    // on failure pages mapped so far are unmapped again
+    pub fn map(&mut self, table: &mut PageTable) -> Option<()> {
+        if self.map_type == MapType::Identical {
+            return self.map_identical(table);
+        }
         for vpn in self.range.iter() {
-            self.map_one(table, vpn);
+            if self.map_one(table, vpn).is_none() {
+                for mapped in Range::new(self.range.start, vpn).iter() {
+                    self.unmap_one(table, mapped);
+                }
+                self.frame_map.remove(&vpn);
+                return None;
+            }
         }
+        Some(())
     }
 
     pub fn unmap(&mut self, table: &mut PageTable) {
+        if self.map_type == MapType::Identical {
+            self.unmap_identical(table, self.range.end);
+            return;
+        }
         for vpn in self.range.iter() {
             self.unmap_one(table, vpn);
         }
     }
 
+    // identical areas use the largest leaves that range and alignment allow
+    fn map_identical(&mut self, table: &mut PageTable) -> Option<()> {
+        let mut vpn = self.range.start;
+        while vpn < self.range.end {
+            let size = PageSize::largest(vpn, PhysPageNum(vpn.0), self.range.end.0 - vpn.0);
+            if table.map_sized(vpn, PhysPageNum(vpn.0), self.perm, size).is_none() {
+                self.unmap_identical(table, vpn);
+                return None;
+            }
+            vpn.0 += size.pages();
+        }
+        Some(())
+    }
+
+    // unmap the leaves of an identical area in [range.start, end)
+    fn unmap_identical(&self, table: &mut PageTable, end: VirtPageNum) {
+        let mut vpn = self.range.start;
+        while vpn < end {
+            let size = PageSize::largest(vpn, PhysPageNum(vpn.0), self.range.end.0 - vpn.0);
+            table.unmap(vpn);
+            vpn.0 += size.pages();
+        }
+    }
+
     pub fn copy_from_bytes(&mut self, data: &[u8]) {
         let mut head = 0;
         let len = data.len();
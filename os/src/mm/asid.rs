@@ -0,0 +1,111 @@
+use core::{arch::asm, sync::atomic::{AtomicUsize, Ordering}};
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::SpinLock;
+use crate::config::PAGE_SIZE_BITS;
+use super::address::VirtPageNum;
+
+// the kernel space always runs with ASID 0
+pub const KERNEL_ASID: usize = 0;
+// SV39 satp holds up to 16 ASID bits at bit 44, a hart may implement fewer
+pub const ASID_SHIFT: usize = 44;
+const ASID_FIELD: usize = 0xffff;
+
+// largest ASID the harts implement, 0 if they have none
+static MAX_ASID: AtomicUsize = AtomicUsize::new(0);
+
+// write all ones into the ASID field of satp and keep the bits that stick
+// called once on hart 0 right after the kernel table is installed
+pub fn probe_asid_bits() {
+    let satp: usize;
+    let probed: usize;
+    unsafe {
+        asm!("csrr {}, satp", out(reg) satp);
+        asm!("csrw satp, {}", in(reg) satp | ASID_FIELD << ASID_SHIFT);
+        asm!("csrr {}, satp", out(reg) probed);
+        asm!("csrw satp, {}", "sfence.vma", in(reg) satp);
+    }
+    MAX_ASID.store(probed >> ASID_SHIFT & ASID_FIELD, Ordering::Relaxed);
+}
+
+// without ASIDs every space runs with ASID 0, so switching spaces must flush the whole TLB
+pub fn has_asids() -> bool {
+    MAX_ASID.load(Ordering::Relaxed) != 0
+}
+
+pub struct AsidAllocator {
+    current: usize,
+    recycled: Vec<usize>,
+}
+
+pub struct AsidTracker(pub usize);
+
+impl AsidAllocator {
+    pub fn new() -> Self {
+        Self {
+            current: KERNEL_ASID + 1,
+            recycled: Vec::new(),
+        }
+    }
+
+    // return none if every ASID is in use
+    pub fn alloc(&mut self) -> Option<AsidTracker> {
+        if !has_asids() {
+            return Some(AsidTracker(KERNEL_ASID));
+        }
+        if let Some(asid) = self.recycled.pop() {
+            Some(AsidTracker(asid))
+        } else if self.current <= MAX_ASID.load(Ordering::Relaxed) {
+            let asid = self.current;
+            self.current += 1;
+            Some(AsidTracker(asid))
+        } else {
+            None
+        }
+    }
+
+    pub fn dealloc(&mut self, asid: usize) {
+        self.recycled.push(asid);
+    }
+}
+
+lazy_static! {
+    pub static ref ASID_ALLOCATOR: SpinLock<AsidAllocator> = SpinLock::new(AsidAllocator::new());
+}
+
+pub fn alloc_asid() -> Option<AsidTracker> {
+    ASID_ALLOCATOR.lock().alloc()
+}
+
+impl AsidTracker {
+    pub fn kernel() -> Self {
+        AsidTracker(KERNEL_ASID)
+    }
+}
+
+impl Drop for AsidTracker {
+    fn drop(&mut self) {
+        if self.0 == KERNEL_ASID {
+            return;
+        }
+        // the next owner must not see stale translations
+        flush_asid(self.0);
+        ASID_ALLOCATOR.lock().dealloc(self.0);
+    }
+}
+
+// flush the translation of one page in the space of asid
+pub fn flush_page(asid: usize, vpn: VirtPageNum) {
+    // sign-extend bit 38 as sfence.vma expects a valid SV39 address
+    let addr = (((vpn.0 << PAGE_SIZE_BITS) << 25) as isize >> 25) as usize;
+    unsafe {
+        asm!("sfence.vma {}, {}", in(reg) addr, in(reg) asid);
+    }
+}
+
+// flush all non-global translations in the space of asid
+pub fn flush_asid(asid: usize) {
+    unsafe {
+        asm!("sfence.vma zero, {}", in(reg) asid);
+    }
+}
@@ -0,0 +1,93 @@
+use super::address::{PhysAddr, PhysPageNum};
+use alloc::alloc::Layout;
+use alloc::vec;
+use alloc::vec::Vec;
+use allocator::buddy_allocator::BuddyAllocatorInner;
+use spin::SpinLock;
+use crate::config::{PAGE_SIZE, PAGE_SIZE_BITS};
+use super::frame_allocator::FrameAllocator;
+
+pub struct BuddyFrameAllocator {
+    inner: SpinLock<BuddyFrameAllocatorInner>
+}
+
+impl FrameAllocator for BuddyFrameAllocator {
+    fn new() -> Self {
+        BuddyFrameAllocator {
+            inner: SpinLock::new(BuddyFrameAllocatorInner::new())
+        }
+    }
+
+    fn init(&self, start: PhysPageNum, end: PhysPageNum) {
+        self.inner.lock().init(start, end);
+    }
+
+    fn alloc(&self) -> Option<PhysPageNum> {
+        self.alloc_contiguous(0)
+    }
+
+    fn dealloc(&self, ppn: PhysPageNum) {
+        self.dealloc_contiguous(ppn, 0);
+    }
+
+    fn alloc_contiguous(&self, order: usize) -> Option<PhysPageNum> {
+        self.inner.lock().alloc(order)
+    }
+
+    fn dealloc_contiguous(&self, ppn: PhysPageNum, order: usize) {
+        self.inner.lock().dealloc(ppn, order);
+    }
+}
+
+pub struct BuddyFrameAllocatorInner {
+    // free blocks are linked through the physical pages themselves
+    buddy: BuddyAllocatorInner,
+    start: usize,
+    // heads[i] is order + 1 if a block starts at frame start + i, otherwise 0
+    heads: Vec<u8>
+}
+
+// the free lists only point into the physical memory managed by the allocator
+unsafe impl Send for BuddyFrameAllocatorInner {}
+
+impl BuddyFrameAllocatorInner {
+    pub fn new() -> Self {
+        BuddyFrameAllocatorInner {
+            buddy: BuddyAllocatorInner::empty(PAGE_SIZE_BITS),
+            start: 0,
+            heads: Vec::new()
+        }
+    }
+
+    pub fn init(&mut self, start: PhysPageNum, end: PhysPageNum) {
+        self.start = start.0;
+        self.heads = vec![0; end.0 - start.0];
+        unsafe {
+            self.buddy.add_space(start.to_addr().0, end.to_addr().0);
+        }
+    }
+
+    fn layout(order: usize) -> Layout {
+        let size = PAGE_SIZE << order;
+        Layout::from_size_align(size, size).unwrap()
+    }
+
+    fn alloc(&mut self, order: usize) -> Option<PhysPageNum> {
+        let ptr = self.buddy.try_alloc(Self::layout(order))?;
+        let ppn = PhysAddr::from(ptr as usize).floor();
+        self.heads[ppn.0 - self.start] = order as u8 + 1;
+        Some(ppn)
+    }
+
+    fn dealloc(&mut self, ppn: PhysPageNum, order: usize) {
+        // validity check, the block must be allocated with the same order
+        let head = ppn.0.checked_sub(self.start)
+            .and_then(|index| self.heads.get_mut(index))
+            .filter(|head| **head as usize == order + 1);
+        match head {
+            Some(head) => *head = 0,
+            None => panic!("Frame ppn={:#x} has not been allocated!", ppn.0),
+        }
+        self.buddy.dealloc(ppn.to_addr().0 as *mut u8, Self::layout(order));
+    }
+}
@@ -1,15 +1,34 @@
 use super::address::PhysPageNum;
 use lazy_static::*;
+#[allow(unused)]
 use super::stack_frame_allocator::StackFrameAllocator;
+use super::buddy_frame_allocator::BuddyFrameAllocator;
 use crate::config::MEMORY_END;
 use super::address::PhysAddr;
 
 pub trait FrameAllocator {
     fn new() -> Self;
-    fn alloc(&self) -> PhysPageNum;
+    fn init(&self, start: PhysPageNum, end: PhysPageNum);
+    // none when physical memory is used up
+    fn alloc(&self) -> Option<PhysPageNum>;
     fn dealloc(&self, ppn: PhysPageNum);
+    // allocate 2^order contiguous frames aligned to their size
+    fn alloc_contiguous(&self, order: usize) -> Option<PhysPageNum> {
+        if order == 0 {
+            self.alloc()
+        } else {
+            None
+        }
+    }
+    fn dealloc_contiguous(&self, ppn: PhysPageNum, order: usize) {
+        assert_eq!(order, 0);
+        self.dealloc(ppn);
+    }
 }
 
+// the frame allocator used by the kernel, StackFrameAllocator can only hand out single frames
+type FrameAllocatorImpl = BuddyFrameAllocator;
+
 // bind PhysPageNum with FrameTracker for RAII
 pub struct  FrameTracker {
     pub ppn: PhysPageNum,
@@ -32,8 +51,30 @@ impl Drop for FrameTracker {
     }
 }
 
+// bind 2^order contiguous frames starting from ppn for RAII
+pub struct ContiguousFrames {
+    pub ppn: PhysPageNum,
+    pub order: usize,
+}
+
+impl ContiguousFrames {
+    pub fn new(ppn: PhysPageNum, order: usize) -> Self {
+        // clean the pages
+        for i in 0..(1 << order) {
+            PhysPageNum(ppn.0 + i).get_bytes_array().fill(0);
+        }
+        Self { ppn, order }
+    }
+}
+
+impl Drop for ContiguousFrames {
+    fn drop(&mut self) {
+        FRAME_ALLOCATOR.dealloc_contiguous(self.ppn, self.order);
+    }
+}
+
 lazy_static! {
-    pub static ref FRAME_ALLOCATOR: StackFrameAllocator = StackFrameAllocator::new();
+    pub static ref FRAME_ALLOCATOR: FrameAllocatorImpl = FrameAllocatorImpl::new();
 }
 
 pub fn init_frame_allocator() {
@@ -44,8 +85,15 @@ pub fn init_frame_allocator() {
         .init(PhysAddr::from(ekernel as usize).ceil(), PhysAddr::from(MEMORY_END).floor());
 }
 
-pub fn frame_alloc() -> FrameTracker {
-    FrameTracker::new(FRAME_ALLOCATOR.alloc())
+pub fn frame_alloc() -> Option<FrameTracker> {
+    FRAME_ALLOCATOR.alloc().map(FrameTracker::new)
+}
+
+// return none if there is no free run of 2^order frames
+pub fn frame_alloc_contiguous(order: usize) -> Option<ContiguousFrames> {
+    FRAME_ALLOCATOR
+        .alloc_contiguous(order)
+        .map(|ppn| ContiguousFrames::new(ppn, order))
 }
 
 fn frame_dealloc(ppn: PhysPageNum) {
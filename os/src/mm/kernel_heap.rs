@@ -1,13 +1,19 @@
-use allocator::buddy_allocator::BuddyAllocator;
+use core::{alloc::Layout, cmp::max, mem};
+use allocator::{buddy_allocator::MAX_BLOCK_SIZE, slab_allocator::SlabAllocator};
+use crate::{config::PAGE_SIZE, println};
+use super::frame_allocator::frame_alloc_contiguous;
 
 const KERNEL_HEAP_SIZE: usize = 0x800_000;
+// minimum block of the buddy layer behind the slab caches
 const KERNEL_HEAP_UNIT: usize = 8;
+// the heap grows by at least 2^KERNEL_HEAP_GROW_ORDER frames when it runs out
+const KERNEL_HEAP_GROW_ORDER: usize = 8;
 
 #[link_section = ".data.heap"]
 static mut KERNEL_HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 
 #[global_allocator]
-static mut KERNEL_HEAP_ALLOCATOR: BuddyAllocator = BuddyAllocator::empty(KERNEL_HEAP_UNIT);
+static mut KERNEL_HEAP_ALLOCATOR: SlabAllocator = SlabAllocator::with_oom_handler(KERNEL_HEAP_UNIT, Some(grow_kernel_heap));
 
 pub fn init_kernel_heap() {
     unsafe {
@@ -18,3 +24,35 @@ pub fn init_kernel_heap() {
             .add_space(begin, end);
     }
 }
+
+// out-of-memory handler, frames given to the heap are never returned
+fn grow_kernel_heap(layout: &Layout) -> bool {
+    // no space could ever serve a block this large, and doubling it below would overflow
+    let block = max(layout.size(), layout.align());
+    if block > MAX_BLOCK_SIZE {
+        return false;
+    }
+    // twice the block size always holds an aligned block next to the bitmap
+    let size = 2 * block.next_power_of_two();
+    let order = max(size.div_ceil(PAGE_SIZE).next_power_of_two().trailing_zeros() as usize, KERNEL_HEAP_GROW_ORDER);
+    let frames = match frame_alloc_contiguous(order) {
+        Some(frames) => frames,
+        None => return false,
+    };
+    let start = frames.ppn.to_addr().0;
+    let added = unsafe { KERNEL_HEAP_ALLOCATOR.add_space(start, start + (PAGE_SIZE << order)) };
+    if added {
+        mem::forget(frames);
+    }
+    added
+}
+
+#[allow(unused)]
+// only for debug
+pub fn show_heap_stats() {
+    let stats = unsafe { KERNEL_HEAP_ALLOCATOR.stats() };
+    for cache in stats.caches.iter() {
+        println!("[kernel heap] size {}: {}/{} objects in {} slabs", cache.size, cache.used, cache.capacity, cache.slabs);
+    }
+    println!("[kernel heap] large: {} allocations, {} bytes", stats.large_allocs, stats.large_bytes);
+}
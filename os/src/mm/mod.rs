@@ -4,4 +4,7 @@ pub mod page_table;
 pub mod frame_allocator;
 pub mod range;
 pub mod address_space;
-mod stack_frame_allocator;
\ No newline at end of file
+pub mod user_ptr;
+pub mod asid;
+mod stack_frame_allocator;
+mod buddy_frame_allocator;
\ No newline at end of file
@@ -1,10 +1,10 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
 use alloc::vec:: Vec;
 use alloc::vec;
 use bitflags::*;
-use core::cmp::min;
-use crate::{config::PAGE_SIZE, print};
+use crate::{config::{CLINT_MSIP, MAX_HARTS}, print, task::processor::hart_id};
 
-use super::{address::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum}, frame_allocator::{frame_alloc, FrameTracker}};
+use super::{address::{PhysPageNum, VirtPageNum}, asid::{alloc_asid, ASID_SHIFT, flush_asid, flush_page, AsidTracker}, frame_allocator::{frame_alloc, FrameTracker}};
 
 bitflags! {
     pub struct PTEFlags: u8 {
@@ -45,51 +45,211 @@ impl PageTableEntry {
     pub fn is_valid(&self) -> bool {
         (self.flags() & PTEFlags::V) != PTEFlags::empty()
     }
+    // a valid pte with any of R/W/X set is a leaf, otherwise it points to the next level
+    pub fn is_leaf(&self) -> bool {
+        self.is_valid() && self.flags().intersects(PTEFlags::R | PTEFlags::W | PTEFlags::X)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+/// size of a leaf mapping: 4KiB page, 2MiB megapage or 1GiB gigapage
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+impl PageSize {
+    // the page table level holding leaves of this size
+    fn level(&self) -> usize {
+        match self {
+            PageSize::Size1G => 0,
+            PageSize::Size2M => 1,
+            PageSize::Size4K => 2,
+        }
+    }
+
+    fn from_level(level: usize) -> Self {
+        match level {
+            0 => PageSize::Size1G,
+            1 => PageSize::Size2M,
+            _ => PageSize::Size4K,
+        }
+    }
+
+    // number of 4KiB pages covered
+    pub fn pages(&self) -> usize {
+        1 << (9 * (2 - self.level()))
+    }
+
+    // the largest leaf mapping vpn to ppn with at most count pages
+    pub fn largest(vpn: VirtPageNum, ppn: PhysPageNum, count: usize) -> Self {
+        [PageSize::Size1G, PageSize::Size2M]
+            .into_iter()
+            .find(|size| {
+                let pages = size.pages();
+                vpn.0 % pages == 0 && ppn.0 % pages == 0 && count >= pages
+            })
+            .unwrap_or(PageSize::Size4K)
+    }
+}
+
+// no user space is running on the hart
+const NO_ASID: usize = usize::MAX;
+
+// array initializers, each element is a fresh atomic
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_RUNNING: AtomicUsize = AtomicUsize::new(NO_ASID);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_TRAPS: AtomicUsize = AtomicUsize::new(0);
+
+// ASID each hart runs in user mode, it is cleared as soon as the hart traps into the kernel
+static RUNNING_ASID: [AtomicUsize; MAX_HARTS] = [NOT_RUNNING; MAX_HARTS];
+// number of traps from user mode of each hart, lets a revoking hart notice a trap
+// even if the hart went back to user mode before RUNNING_ASID was seen cleared
+static USER_TRAPS: [AtomicUsize; MAX_HARTS] = [NO_TRAPS; MAX_HARTS];
+
+// called first thing on a trap from user mode, without taking any lock
+pub fn leave_user(hart: usize) {
+    RUNNING_ASID[hart].store(NO_ASID, Ordering::SeqCst);
+    USER_TRAPS[hart].fetch_add(1, Ordering::SeqCst);
+}
+
+// raise a software interrupt on hart, forwarded by M-mode to S-mode
+fn send_ipi(hart: usize) {
+    unsafe {
+        ((CLINT_MSIP + 4 * hart) as *mut u32).write_volatile(1);
+    }
 }
 
 pub struct PageTable {
     root_ppn: PhysPageNum,
-    frames: Vec<FrameTracker> 
+    frames: Vec<FrameTracker>,
+    // recycled with a flush of its translations when the table is dropped
+    asid: AsidTracker,
+    // bit i is set if hart i may cache translations changed since it last activated the table
+    stale_harts: AtomicUsize,
 }
 
 impl PageTable {
-    pub fn new() -> Self {
-        let root_frame = frame_alloc();
-        Self {
+    // return none if frames or ASIDs run out
+    pub fn new() -> Option<Self> {
+        Self::with_asid(alloc_asid()?)
+    }
+
+    pub fn new_kernel() -> Option<Self> {
+        Self::with_asid(AsidTracker::kernel())
+    }
+
+    fn with_asid(asid: AsidTracker) -> Option<Self> {
+        let root_frame = frame_alloc()?;
+        Some(Self {
             root_ppn: root_frame.ppn,
-            frames: vec![root_frame]
-        }
+            frames: vec![root_frame],
+            asid,
+            // a recycled ASID may still be cached by any hart
+            stale_harts: AtomicUsize::new(usize::MAX),
+        })
     }
 
     pub fn  get_satp(&self) -> usize {
-        self.root_ppn.0 | (8usize << 60) // set MODE = 8 for SV39
+        self.root_ppn.0 | (self.asid.0 << ASID_SHIFT) | (8usize << 60) // set MODE = 8 for SV39
+    }
+
+    // called before hart runs the table, its stale translations are dropped
+    // harts running the table right now only see changes after their next activation
+    pub fn activate(&self, hart: usize) {
+        let bit = 1 << hart;
+        if self.stale_harts.fetch_and(!bit, Ordering::SeqCst) & bit != 0 {
+            flush_asid(self.asid.0);
+        }
+    }
+
+    // activate a user table hart is about to return to user mode with
+    // the ASID is published before the stale bits are read, so a hart revoking a mapping
+    // either sees it and interrupts this hart or sets the bit in time for the check
+    pub fn activate_user(&self, hart: usize) {
+        RUNNING_ASID[hart].store(self.asid.0, Ordering::SeqCst);
+        self.activate(hart);
+    }
+
+    // flush vpn on the current hart, the others flush when they activate the table
+    // enough when a mapping is added, as no hart can cache a missing translation
+    fn invalidate(&self, vpn: VirtPageNum) {
+        flush_page(self.asid.0, vpn);
+        self.stale_harts.fetch_or(!(1 << hart_id()), Ordering::SeqCst);
+    }
+
+    // invalidate vpn and wait until no other hart can still use the old translation
+    // a hart in user mode with the ASID is interrupted, it flushes when it activates the table again
+    // the caller holds the address space lock, so those harts cannot return to this table meanwhile
+    // without ASIDs every space has ASID 0, but then every trap flushes the whole TLB
+    fn revoke(&self, vpn: VirtPageNum) {
+        self.invalidate(vpn);
+        let current = hart_id();
+        for hart in (0..MAX_HARTS).filter(|hart| *hart != current) {
+            let traps = USER_TRAPS[hart].load(Ordering::SeqCst);
+            if RUNNING_ASID[hart].load(Ordering::SeqCst) != self.asid.0 {
+                continue;
+            }
+            send_ipi(hart);
+            while RUNNING_ASID[hart].load(Ordering::SeqCst) == self.asid.0
+                && USER_TRAPS[hart].load(Ordering::SeqCst) == traps
+            {
+                core::hint::spin_loop();
+            }
+        }
     }
 
     // NOTE that map() ensures that the mapped pte is valid
-    pub fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
-        let pte = self.create_pte(vpn);
+    // return none if there is no frame left for page tables
+    pub fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) -> Option<()> {
+        self.map_sized(vpn, ppn, flags, PageSize::Size4K)
+    }
+
+    // map a leaf of the given size, vpn and ppn must be aligned to it
+    pub fn map_sized(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags, size: PageSize) -> Option<()> {
+        assert!(vpn.0 % size.pages() == 0 && ppn.0 % size.pages() == 0);
+        let pte = self.create_pte(vpn, size.level())?;
         *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
+        self.invalidate(vpn);
+        Some(())
     }
 
+    // change the mapping of vpn, which must be a 4KiB page with its page tables already
+    pub fn remap(&self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
+        match self.find_pte(vpn) {
+            Some((pte, PageSize::Size4K)) => *pte = PageTableEntry::new(ppn, flags | PTEFlags::V),
+            _ => panic!("[page table] The vpn:{} is not valid when remapping", vpn.0),
+        }
+        self.revoke(vpn);
+    }
+
+    // remove the leaf containing vpn, which may cover a whole megapage or gigapage
     pub fn unmap(&self, vpn: VirtPageNum) {
-        if let Some(pte) = self.find_pte(vpn) {
+        if let Some((pte, _)) = self.find_pte(vpn) {
             *pte = PageTableEntry::empty();
+            self.revoke(vpn);
         } else {
             panic!("[page table] The vpn:{} is not valid when unmapping", vpn.0);
         }
     }
 
-    // find vpn's pte, create new pages if necessary
-    fn create_pte(&mut self, vpn: VirtPageNum) -> &mut PageTableEntry {
+    // find vpn's pte at the given level, create new pages if necessary
+    // return none if a new page cannot be allocated
+    fn create_pte(&mut self, vpn: VirtPageNum, level: usize) -> Option<&mut PageTableEntry> {
         let index = vpn.get_index();
         let mut ppn = self.root_ppn;
         for i in 0..3 {
             let pte = &mut ppn.get_pte_array()[index[i]];
-            if i == 2 {
-                return pte;
+            if i == level {
+                return Some(pte);
+            }
+            if pte.is_leaf() {
+                panic!("[page table] The vpn:{} is already mapped by a huge page", vpn.0);
             }
             if !pte.is_valid() {
-                let frame = frame_alloc();
+                let frame = frame_alloc()?;
                 *pte = PageTableEntry::new(frame.ppn, PTEFlags::V);
                 self.frames.push(frame);
             }
@@ -98,15 +258,15 @@ impl PageTable {
         unreachable!();
     }
 
-    // find vpn's pte, return none if its page table is invalid
-    // however even if !pte.is_valid() we still return Some(pte)
-    fn find_pte(&self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
+    // find the leaf pte covering vpn and its size, return none if its page table is invalid
+    // however even if !pte.is_valid() we still return Some(pte) at the last level
+    fn find_pte(&self, vpn: VirtPageNum) -> Option<(&mut PageTableEntry, PageSize)> {
         let index = vpn.get_index();
         let mut ppn = self.root_ppn;
         for i in 0..3 {
             let pte = &mut ppn.get_pte_array()[index[i]];
-            if i == 2 {
-                return Some(pte);
+            if i == 2 || pte.is_leaf() {
+                return Some((pte, PageSize::from_level(i)));
             }
             if !pte.is_valid() {
                 return None;
@@ -117,81 +277,15 @@ impl PageTable {
     }
 }
 
-// used for kernel to access user's page
 impl PageTable {
-    pub fn from_satp(satp: usize) -> Self{
-        Self {
-            root_ppn: PhysPageNum::from(satp & ((1usize << 44) - 1)),
-            frames: Vec::new()
-        }
-    }
-
+    // the returned pte maps vpn itself even if it lies inside a huge page
     pub fn translate_vpn(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.find_pte(vpn)
-            .map(|pte| {pte.clone()})
+            .map(|(pte, size)| {
+                let offset = vpn.0 & (size.pages() - 1);
+                PageTableEntry::new(PhysPageNum(pte.ppn().0 + offset), pte.flags())
+            })
     }
-
-    pub fn translate_va(&self, va: VirtAddr) -> Option<PhysAddr> {
-        if let Some(pte) = self.translate_vpn(va.floor()) {
-            if !pte.is_valid() {
-                return None;
-            }
-            let ppn = pte.ppn();
-            let offset = va.offset();
-            Some(PhysAddr(ppn.0 * PAGE_SIZE + offset))
-        } else {
-            None
-        }
-    }
-}
-
-pub fn get_user_byte_buffer(satp: usize, ptr: *const u8, len: usize) -> Vec<u8> {
-    let mut res = Vec::new();
-    let mut start = ptr as usize;
-    let end = start + len;
-    let page_table = PageTable::from_satp(satp);
-    while start < end {
-        let strat_va = VirtAddr(start);
-        let vpn = strat_va.floor();
-        let ppn = page_table.translate_vpn(vpn).unwrap().ppn();
-        let offset = strat_va.offset();
-        let amount = min(end - start, PAGE_SIZE - offset);
-        let bytes = ppn.get_bytes_array();
-        for byte in bytes[offset..offset + amount].iter() {
-            res.push(*byte);
-        }
-        start += amount;
-    }
-    res
-}
-
-pub fn copy_bytes_to_user(satp: usize, src: *const u8, dst: usize, len: usize) {
-    let mut start = dst;
-    let end = start + len;
-    let page_table = PageTable::from_satp(satp);
-    let mut src_ptr = src;
-    while start < end {
-        let strat_va = VirtAddr(start);
-        let vpn = strat_va.floor();
-        let ppn = page_table.translate_vpn(vpn).unwrap().ppn();
-        let offset = strat_va.offset();
-        let amount = min(end - start, PAGE_SIZE - offset);
-        let bytes = ppn.get_bytes_array();
-        for i in 0..amount {
-            bytes[offset + i] = unsafe { *src_ptr.add(i) };
-        }
-        start += amount;
-        src_ptr = unsafe { src_ptr.add(amount) };
-    }
-}
-
-pub fn translate_refmut<T>(satp: usize, ptr: *mut T) -> &'static mut T {
-    let page_table = PageTable::from_satp(satp);
-    let va = ptr as usize;
-    page_table
-        .translate_va(VirtAddr(va))
-        .unwrap()
-        .get_mut()
 }
 
 impl PageTable {
@@ -14,17 +14,21 @@ impl FrameAllocator for StackFrameAllocator {
         }
     }
 
-    fn alloc(&self) -> PhysPageNum {
+    fn init(&self, start: PhysPageNum, end: PhysPageNum) {
+        self.inner.lock().init(start, end);
+    }
+
+    fn alloc(&self) -> Option<PhysPageNum> {
         let mut inner = self.inner.lock();
         if let Some(ppn) = inner.recycled.pop() {
-            ppn.into()
+            Some(ppn.into())
         } else {
             if inner.current < inner.end {
                 let ppn = inner.current;
                 inner.current += 1;
-                ppn.into()
+                Some(ppn.into())
             } else {
-                panic!("[frame allocator] out of memory!")
+                None
             }
         }
     }
@@ -39,11 +43,6 @@ impl FrameAllocator for StackFrameAllocator {
     }
 }
 
-impl StackFrameAllocator {
-    pub fn init(&self, start: PhysPageNum, end: PhysPageNum) {
-        self.inner.lock().init(start, end);
-    }
-}
 pub struct StackFrameAllocatorInner {
     current: usize,
     end: usize,
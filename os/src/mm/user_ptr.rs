@@ -0,0 +1,138 @@
+// checked access to memory of current task from syscalls
+use core::{cmp::min, marker::PhantomData, mem::{size_of, MaybeUninit}, slice};
+use alloc::vec::Vec;
+use crate::{config::PAGE_SIZE, task::processor::current_task};
+use super::address::VirtAddr;
+
+// returned by syscalls given a bad user address
+pub const EFAULT: isize = -14;
+
+// a user address is unmapped, kernel-only or lacks the needed permission
+#[derive(Debug)]
+pub struct Fault;
+
+impl Fault {
+    pub fn code(&self) -> isize {
+        EFAULT
+    }
+}
+
+// call f on each piece of [start, start + len) within one page, with the
+// kernel view of that piece, after checking the page allows the access
+fn for_each_page(start: usize, len: usize, write: bool, mut f: impl FnMut(&mut [u8])) -> Result<(), Fault> {
+    let end = start.checked_add(len).ok_or(Fault)?;
+    let task = current_task().unwrap();
+    let user_space = task.user_space();
+    let mut space = user_space.lock();
+    let mut current = start;
+    while current < end {
+        let va = VirtAddr(current);
+        let ppn = space.user_page(va.floor(), write).ok_or(Fault)?;
+        let offset = va.offset();
+        let amount = min(end - current, PAGE_SIZE - offset);
+        f(&mut ppn.get_bytes_array()[offset..offset + amount]);
+        current += amount;
+    }
+    Ok(())
+}
+
+// len bytes of user memory at ptr
+#[derive(Clone, Copy)]
+pub struct UserSlice {
+    ptr: usize,
+    len: usize,
+}
+
+impl UserSlice {
+    pub fn new(ptr: usize, len: usize) -> Self {
+        Self { ptr, len }
+    }
+
+    // len comes from the user, so the buffer only grows as pages are found readable
+    // callers bound len, larger slices are copied through read_into
+    pub fn read(&self) -> Result<Vec<u8>, Fault> {
+        let mut data = Vec::with_capacity(min(self.len, PAGE_SIZE));
+        for_each_page(self.ptr, self.len, false, |bytes| data.extend_from_slice(bytes))?;
+        Ok(data)
+    }
+
+    // copy the front of the slice into buf, return the number of bytes copied
+    pub fn read_into(&self, buf: &mut [u8]) -> Result<usize, Fault> {
+        let mut head = 0;
+        for_each_page(self.ptr, min(self.len, buf.len()), false, |bytes| {
+            buf[head..head + bytes.len()].copy_from_slice(bytes);
+            head += bytes.len();
+        })?;
+        Ok(head)
+    }
+
+    // copy data to the front of the slice, extra bytes are dropped
+    pub fn write(&self, data: &[u8]) -> Result<(), Fault> {
+        let mut head = 0;
+        for_each_page(self.ptr, min(self.len, data.len()), true, |bytes| {
+            bytes.copy_from_slice(&data[head..head + bytes.len()]);
+            head += bytes.len();
+        })
+    }
+
+    // check the whole slice can be written without writing anything,
+    // used before an operation that cannot be undone
+    pub fn check_writable(&self) -> Result<(), Fault> {
+        for_each_page(self.ptr, self.len, true, |_| {})
+    }
+
+    pub fn read_words(&self) -> Result<Vec<usize>, Fault> {
+        let bytes = self.read()?;
+        Ok(bytes
+            .chunks_exact(size_of::<usize>())
+            .map(|word| usize::from_ne_bytes(word.try_into().unwrap()))
+            .collect())
+    }
+
+    pub fn write_words(&self, data: &[usize]) -> Result<(), Fault> {
+        let bytes: Vec<u8> = data.iter().flat_map(|word| word.to_ne_bytes()).collect();
+        self.write(&bytes)
+    }
+}
+
+// a value of type T in user memory, it need not be aligned
+pub struct UserPtr<T: Copy> {
+    ptr: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Copy> UserPtr<T> {
+    pub fn new(ptr: *mut T) -> Self {
+        Self {
+            ptr: ptr as usize,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.ptr == 0
+    }
+
+    fn slice(&self) -> UserSlice {
+        UserSlice::new(self.ptr, size_of::<T>())
+    }
+
+    #[allow(unused)]
+    pub fn read(&self) -> Result<T, Fault> {
+        let bytes = self.slice().read()?;
+        let mut value = MaybeUninit::<T>::uninit();
+        unsafe {
+            core::ptr::copy_nonoverlapping(bytes.as_ptr(), value.as_mut_ptr() as *mut u8, size_of::<T>());
+            Ok(value.assume_init())
+        }
+    }
+
+    pub fn check_writable(&self) -> Result<(), Fault> {
+        self.slice().check_writable()
+    }
+
+    pub fn write(&self, value: T) -> Result<(), Fault> {
+        let bytes = unsafe { slice::from_raw_parts(&value as *const T as *const u8, size_of::<T>()) };
+        self.slice().write(bytes)
+    }
+}
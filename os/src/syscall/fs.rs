@@ -1,32 +1,58 @@
-use crate::{io::console::getchar, mm::page_table::{get_user_byte_buffer, translate_refmut}, print, task::processor::current_user_satp};
+use alloc::vec;
+use crate::{config::PAGE_SIZE, io::console::getchar, mm::user_ptr::{UserPtr, UserSlice}, print};
 
 const FD_STDIN: usize = 0;
 const FD_STDOUT: usize = 1;
 
 pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     match fd {
+        // copy through one page at a time, a character split between two
+        // pages is carried to the front of the buffer for the next round
         FD_STDOUT => {
-            let buffer = get_user_byte_buffer(current_user_satp(), buf, len);
-            print!("{}", core::str::from_utf8(buffer.as_slice()).unwrap());
+            let mut buffer = vec![0u8; PAGE_SIZE];
+            let mut carry = 0;
+            let mut done = 0;
+            while done < len {
+                let chunk = UserSlice::new(buf as usize + done, len - done);
+                let amount = match chunk.read_into(&mut buffer[carry..]) {
+                    Ok(amount) => amount,
+                    Err(err) => return err.code(),
+                };
+                done += amount;
+                let filled = carry + amount;
+                let valid = match core::str::from_utf8(&buffer[..filled]) {
+                    Ok(_) => filled,
+                    Err(err) if err.error_len().is_none() && done < len => err.valid_up_to(),
+                    Err(_) => return -1,
+                };
+                print!("{}", core::str::from_utf8(&buffer[..valid]).unwrap());
+                buffer.copy_within(valid..filled, 0);
+                carry = filled - valid;
+            }
             len as isize
         }
-        _ => {  
-            panic!("Unsupported fd in sys_write!");
-        }
+        _ => -1,
     }
 }
 
 pub fn sys_read(fd: usize, buf: *mut u8, len: usize) -> isize {
     match fd {
-        FD_STDIN => {
-            assert_eq!(len, 1, "only support sys_read with len=1 from STDIN");
-            let c = getchar();
-            let user_buf = translate_refmut(current_user_satp(), buf);
-            *user_buf = c;
-            1
-        }
-        _ => {
-            panic!("Unsupported fd in sys_read!");
+        // only support sys_read with len=1 from STDIN
+        FD_STDIN if len == 1 => {
+            let user_buf = UserPtr::new(buf);
+            // check buf before a character is consumed
+            if let Err(err) = user_buf.check_writable() {
+                return err.code();
+            }
+            let c = match getchar() {
+                Some(c) => c,
+                None => return -1,
+            };
+            match user_buf.write(c) {
+                Ok(()) => 1,
+                Err(err) => err.code(),
+            }
         }
+        _ => -1,
     }
 }
@@ -2,10 +2,33 @@ pub const SYSCALL_WRITE: usize = 1;
 pub const SYSCALL_EXIT: usize = 2;
 pub const SYSCALL_YIELD: usize = 3;
 pub const SYSCALL_RECV: usize = 4;
-pub const SYSCALL_SENDRECV: usize = 5;
+pub const SYSCALL_REPLY: usize = 5;
 pub const SYSCALL_FORK: usize = 6;
 pub const SYSCALL_EXEC: usize = 7;
 pub const SYSCALL_WAITPID: usize = 8;
 pub const SYSCALL_READ: usize = 9;
 pub const SYSCALL_GETPID: usize = 10;
 pub const SYSCALL_GETTIME: usize = 11;
+pub const SYSCALL_CALL: usize = 12;
+pub const SYSCALL_ENDPOINT_CREATE: usize = 13;
+pub const SYSCALL_SEND: usize = 14;
+pub const SYSCALL_TRY_RECV: usize = 15;
+pub const SYSCALL_CAP_DUP: usize = 16;
+pub const SYSCALL_CAP_DROP: usize = 17;
+pub const SYSCALL_CAP_TASK: usize = 18;
+pub const SYSCALL_CAP_MEMORY: usize = 19;
+pub const SYSCALL_CAP_IDENTIFY: usize = 20;
+pub const SYSCALL_MEM_MAP: usize = 21;
+pub const SYSCALL_MEM_UNMAP: usize = 22;
+pub const SYSCALL_IPC_TIMEOUT: usize = 23;
+pub const SYSCALL_MMAP: usize = 24;
+pub const SYSCALL_MUNMAP: usize = 25;
+pub const SYSCALL_MPROTECT: usize = 26;
+pub const SYSCALL_BRK: usize = 27;
+pub const SYSCALL_GET_PRIORITY: usize = 28;
+pub const SYSCALL_SET_PRIORITY: usize = 29;
+pub const SYSCALL_THREAD_CREATE: usize = 30;
+pub const SYSCALL_THREAD_EXIT: usize = 31;
+pub const SYSCALL_THREAD_JOIN: usize = 32;
+pub const SYSCALL_GETTID: usize = 33;
+pub const SYSCALL_SLEEP: usize = 34;
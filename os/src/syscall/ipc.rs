@@ -1,34 +1,309 @@
-use core::cmp::min;
-
-use alloc::vec::Vec;
-
-use crate::{ipc::RPC_BUFFER, mm::page_table::{copy_bytes_to_user, get_user_byte_buffer}, println, task::{block_current_and_run_next, processor::current_user_satp}};
-
-// receive len * usize at ptr, return sender's pid
-pub fn sys_recv(ptr: usize, len: usize) -> isize {
-    let mut ipc = RPC_BUFFER.lock();
-    ipc.callee = None;
-    drop(ipc);
-    block_current_and_run_next();
-    let ipc = RPC_BUFFER.lock();
-    let data = &ipc.data;
-    copy_bytes_to_user(current_user_satp(), data.as_ptr() as *const u8, ptr, min(data.len(), len)*8);
-    ipc.sender as isize
-}
-
-// only for service
-// send len * usize at send and receive len * usize at recv
-pub fn sys_sendrecv(send: usize, send_len: usize, recv: usize, recv_len: usize) -> isize {
-    let tosend = get_user_byte_buffer(current_user_satp(), send as *const u8, send_len * 8);
-    let (ptr, len, capa) = tosend.into_raw_parts();
-    let mut rpc = RPC_BUFFER.lock();
-    rpc.callee = None;
-    unsafe {
-        rpc.data = Vec::from_raw_parts(ptr as *mut usize, len/8, capa/8);
-    }
-    drop(rpc);
-    block_current_and_run_next();
-    let data = &RPC_BUFFER.lock().data;
-    copy_bytes_to_user(current_user_satp(), data.as_ptr() as *const u8, recv, min(data.len(), recv_len)*8);
+use alloc::{sync::{Arc, Weak}, vec::Vec};
+
+use crate::{config::{CLOCK_FREQ, IPC_MAX_WORDS}, cap::{CapObject, CapRights, Capability, MemoryObject, NO_CAP}, ipc::{Endpoint, IpcError, Reply, RpcBuffer}, mm::{address::{VirtAddr, VirtPageNum}, address_space::MapArea, user_ptr::UserSlice}, task::processor::{current_task, current_trap_cx}};
+
+// len * usize of user memory at ptr
+fn user_words(ptr: usize, len: usize) -> UserSlice {
+    UserSlice::new(ptr, len.saturating_mul(8))
+}
+
+// a message to send, return -6 if it is longer than IPC_MAX_WORDS
+fn get_user_words(ptr: usize, len: usize) -> Result<Vec<usize>, isize> {
+    if len > IPC_MAX_WORDS {
+        return Err(IpcError::TooLong.code());
+    }
+    user_words(ptr, len).read_words().map_err(|err| err.code())
+}
+
+// a buffer to receive into, no message fills more than IPC_MAX_WORDS of it
+fn recv_words(ptr: usize, len: usize) -> UserSlice {
+    user_words(ptr, len.min(IPC_MAX_WORDS))
+}
+
+// endpoint of current task's handle if it carries rights
+fn get_endpoint(handle: usize, rights: CapRights) -> Option<Arc<Endpoint>> {
+    let caps = current_task().unwrap().caps();
+    let caps = caps.lock();
+    caps.get(handle)?.endpoint(rights)
+}
+
+// capability of handle to transfer over ipc, it must carry GRANT
+fn get_transfer(handle: usize) -> Result<Option<Capability>, ()> {
+    if handle == NO_CAP {
+        return Ok(None);
+    }
+    let caps = current_task().unwrap().caps();
+    let caps = caps.lock();
+    match caps.get(handle) {
+        Some(cap) if cap.rights.contains(CapRights::GRANT) => Ok(Some(cap.clone())),
+        _ => Err(()),
+    }
+}
+
+// install a received capability, its handle is returned in a1
+fn accept_transfer(cap: Option<Capability>) {
+    let handle = cap.map_or(NO_CAP, |cap| {
+        current_task().unwrap().inner.lock().caps.lock().insert(cap)
+    });
+    current_trap_cx().x[11] = handle;
+}
+
+// current task's ipc timeout in ticks
+fn ipc_timeout() -> Option<usize> {
+    match current_task().unwrap().inner.lock().ipc_timeout {
+        0 => None,
+        ms => Some(ms.saturating_mul(CLOCK_FREQ / 1000)),
+    }
+}
+
+// install the reply token of a request, its handle is returned in a2
+fn accept_reply(reply: Option<Arc<Reply>>) {
+    let handle = reply.map_or(NO_CAP, |reply| {
+        let cap = Capability::new(CapObject::Reply(reply), CapRights::SEND);
+        current_task().unwrap().inner.lock().caps.lock().insert(cap)
+    });
+    current_trap_cx().x[12] = handle;
+}
+
+// a1 and a2 hold NO_CAP unless a message is delivered
+fn clear_received() {
+    let cx = current_trap_cx();
+    cx.x[11] = NO_CAP;
+    cx.x[12] = NO_CAP;
+}
+
+// a message that cannot be copied out is dropped, a waiting caller fails with Closed
+fn deliver(message: RpcBuffer, ptr: usize, len: usize) -> isize {
+    if let Err(err) = recv_words(ptr, len).write_words(&message.data) {
+        return err.code();
+    }
+    accept_transfer(message.cap);
+    accept_reply(message.reply);
+    message.sender as isize
+}
+
+// receive len * usize at ptr from endpoint handle ep, return sender's pid
+// the reply token of a call is returned in a2, NO_CAP for asynchronous messages
+// on error both a1 and a2 are NO_CAP
+// return -3 if nothing arrives within the ipc timeout
+pub fn sys_recv(ep: usize, ptr: usize, len: usize) -> isize {
+    clear_received();
+    if let Err(err) = recv_words(ptr, len).check_writable() {
+        return err.code();
+    }
+    if let Some(endpoint) = get_endpoint(ep, CapRights::RECV) {
+        match endpoint.recv(ipc_timeout()) {
+            Ok(message) => deliver(message, ptr, len),
+            Err(err) => err.code(),
+        }
+    } else {
+        -1
+    }
+}
+
+// receive like sys_recv but return -2 at once if nothing is queued
+pub fn sys_try_recv(ep: usize, ptr: usize, len: usize) -> isize {
+    clear_received();
+    if let Err(err) = recv_words(ptr, len).check_writable() {
+        return err.code();
+    }
+    if let Some(endpoint) = get_endpoint(ep, CapRights::RECV) {
+        if let Some(message) = endpoint.try_recv() {
+            deliver(message, ptr, len)
+        } else {
+            -2
+        }
+    } else {
+        -1
+    }
+}
+
+// answer the caller of reply token with len * usize at ptr and capability cap
+// the token is consumed, return -4 if the caller no longer waits, -6 if len > IPC_MAX_WORDS
+pub fn sys_reply(token: usize, ptr: usize, len: usize, cap: usize) -> isize {
+    let data = match get_user_words(ptr, len) {
+        Ok(data) => data,
+        Err(code) => return code,
+    };
+    let cap = match get_transfer(cap) {
+        Ok(cap) => cap,
+        Err(()) => return -1,
+    };
+    let task = current_task().unwrap();
+    let caps = task.caps();
+    let mut caps = caps.lock();
+    let reply = match caps.get(token).map(|cap| &cap.object) {
+        Some(CapObject::Reply(reply)) => reply.clone(),
+        _ => return -1,
+    };
+    caps.remove(token);
+    drop(caps);
+    let message = RpcBuffer {
+        sender: task.taskid.0,
+        data,
+        cap,
+        reply: None,
+    };
+    if reply.send(message) {
+        0
+    } else {
+        IpcError::Closed.code()
+    }
+}
+
+// send send_len * usize at send and capability cap to ep
+// and receive its reply at recv, return the length of the reply
+// return -3 if no reply comes within the ipc timeout, -4 if ep is closed, -6 if send_len > IPC_MAX_WORDS
+pub fn sys_call(ep: usize, send: usize, send_len: usize, recv: usize, recv_len: usize, cap: usize) -> isize {
+    clear_received();
+    let data = match get_user_words(send, send_len) {
+        Ok(data) => data,
+        Err(code) => return code,
+    };
+    if let Err(err) = recv_words(recv, recv_len).check_writable() {
+        return err.code();
+    }
+    if let (Some(endpoint), Ok(cap)) = (get_endpoint(ep, CapRights::SEND), get_transfer(cap)) {
+        match endpoint.call(data, cap, ipc_timeout()) {
+            Ok(reply) => {
+                let len = reply.data.len() as isize;
+                match deliver(reply, recv, recv_len) {
+                    ret if ret < 0 => ret,
+                    _ => len,
+                }
+            }
+            Err(err) => err.code(),
+        }
+    } else {
+        -1
+    }
+}
+
+// post len * usize at ptr and capability cap to ep without waiting
+// return -2 if its queue is full, -4 if ep is closed, -6 if len > IPC_MAX_WORDS
+pub fn sys_send(ep: usize, ptr: usize, len: usize, cap: usize) -> isize {
+    let data = match get_user_words(ptr, len) {
+        Ok(data) => data,
+        Err(code) => return code,
+    };
+    if let (Some(endpoint), Ok(cap)) = (get_endpoint(ep, CapRights::SEND), get_transfer(cap)) {
+        let sender = current_task().unwrap().taskid.0;
+        match endpoint.send_async(sender, data, cap) {
+            Ok(()) => 0,
+            Err(err) => err.code(),
+        }
+    } else {
+        -1
+    }
+}
+
+// create an endpoint, return a handle with full rights
+pub fn sys_endpoint_create() -> isize {
+    let cap = Capability::new(
+        CapObject::Endpoint(Arc::new(Endpoint::new())),
+        CapRights::all(),
+    );
+    current_task().unwrap().inner.lock().caps.lock().insert(cap) as isize
+}
+
+// copy handle with rights restricted to rights, return the new handle
+pub fn sys_cap_dup(handle: usize, rights: usize) -> isize {
+    let caps = current_task().unwrap().caps();
+    let mut caps = caps.lock();
+    if let Some(cap) = caps.get(handle) {
+        let cap = cap.derive(CapRights::from_bits_truncate(rights));
+        caps.insert(cap) as isize
+    } else {
+        -1
+    }
+}
+
+pub fn sys_cap_drop(handle: usize) -> isize {
+    let task = current_task().unwrap();
+    let inner = task.inner.lock();
+    if inner.caps.lock().remove(handle).is_some() {
+        0
+    } else {
+        -1
+    }
+}
+
+// return a handle to current task itself
+pub fn sys_cap_task() -> isize {
+    let task = current_task().unwrap();
+    let cap = Capability::new(CapObject::Task(Arc::downgrade(&task)), CapRights::GRANT);
+    let handle = task.inner.lock().caps.lock().insert(cap);
+    handle as isize
+}
+
+// return a handle sharing the pages of [start, start + len) of current task
+pub fn sys_cap_memory(start: usize, len: usize) -> isize {
+    let task = current_task().unwrap();
+    let inner = task.inner.lock();
+    let start_vpn = VirtAddr(start).floor();
+    let end_vpn = VirtAddr(start + len).ceil();
+    let shared = inner.user_space.lock().share_frames(start_vpn, end_vpn);
+    if let Some((frames, perm)) = shared {
+        if frames.is_empty() {
+            return -1;
+        }
+        let memory = MemoryObject { frames, perm };
+        let cap = Capability::new(CapObject::Memory(Arc::new(memory)), CapRights::GRANT);
+        inner.caps.lock().insert(cap) as isize
+    } else {
+        -1
+    }
+}
+
+// map the pages of memory handle at page-aligned addr of current task
+pub fn sys_mem_map(handle: usize, addr: usize) -> isize {
+    let task = current_task().unwrap();
+    let inner = task.inner.lock();
+    let memory = match inner.caps.lock().get(handle).map(|cap| &cap.object) {
+        Some(CapObject::Memory(memory)) => memory.clone(),
+        _ => return -1,
+    };
+    let start = VirtAddr(addr);
+    if start.offset() != 0 {
+        return -1;
+    }
+    let start_vpn = start.floor();
+    let end_vpn = VirtPageNum(start_vpn.0 + memory.frames.len());
+    let mut space = inner.user_space.lock();
+    if !space.is_free(start_vpn, end_vpn) {
+        return -1;
+    }
+    let area = MapArea::new_shared(start_vpn, memory.frames.clone(), memory.perm);
+    if space.push(area, None).is_some() {
+        0
+    } else {
+        -1
+    }
+}
+
+// unmap pages mapped by sys_mem_map at addr
+pub fn sys_mem_unmap(addr: usize) -> isize {
+    let task = current_task().unwrap();
+    let user_space = task.user_space();
+    if user_space.lock().remove_shared_area(VirtAddr(addr).floor()) {
+        0
+    } else {
+        -1
+    }
+}
+
+// return the pid of a task handle, -1 if it is not a live task
+pub fn sys_cap_identify(handle: usize) -> isize {
+    let caps = current_task().unwrap().caps();
+    let caps = caps.lock();
+    match caps.get(handle).map(|cap| &cap.object) {
+        Some(CapObject::Task(target)) => Weak::upgrade(target).map_or(-1, |t| t.taskid.0 as isize),
+        _ => -1,
+    }
+}
+
+// set timeout of later calls and receives of current task in ms, 0 for none
+pub fn sys_ipc_timeout(ms: usize) -> isize {
+    current_task().unwrap().inner.lock().ipc_timeout = ms;
     0
 }
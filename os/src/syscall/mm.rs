@@ -0,0 +1,105 @@
+use crate::{config::{PAGE_SIZE, USER_SPACE_END}, mm::{address::{VirtAddr, VirtPageNum}, address_space::{MapArea, MapType}, page_table::PTEFlags}, task::processor::current_task};
+
+const PROT_READ: usize = 1 << 0;
+const PROT_WRITE: usize = 1 << 1;
+const PROT_EXEC: usize = 1 << 2;
+
+// page permission of prot, none for PROT_NONE or write-only pages which SV39 cannot map
+fn prot_to_perm(prot: usize) -> Option<PTEFlags> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 || prot == 0 {
+        return None;
+    }
+    if prot & PROT_WRITE != 0 && prot & PROT_READ == 0 {
+        return None;
+    }
+    // PROT_* bits line up with R, W and X
+    Some(PTEFlags::from_bits_truncate((prot << 1) as u8) | PTEFlags::U)
+}
+
+// page range of [addr, addr + len), addr must be page-aligned
+fn page_range(addr: usize, len: usize) -> Option<(VirtPageNum, VirtPageNum)> {
+    if addr % PAGE_SIZE != 0 || len == 0 || len > USER_SPACE_END || addr > USER_SPACE_END - len {
+        return None;
+    }
+    Some((VirtAddr(addr).floor(), VirtAddr(addr + len).ceil()))
+}
+
+// map len bytes of zeroed memory at addr, or where the kernel finds room if addr is 0
+// pages are allocated on first access, return the start address
+pub fn sys_mmap(addr: usize, len: usize, prot: usize) -> isize {
+    let perm = match prot_to_perm(prot) {
+        Some(perm) => perm,
+        None => return -1,
+    };
+    let task = current_task().unwrap();
+    let user_space = task.user_space();
+    let mut space = user_space.lock();
+    let (start, end) = if addr == 0 {
+        if len == 0 || len > USER_SPACE_END {
+            return -1;
+        }
+        let pages = (len + PAGE_SIZE - 1) / PAGE_SIZE;
+        match space.find_free(pages) {
+            Some(start) => (start, VirtPageNum(start.0 + pages)),
+            None => return -1,
+        }
+    } else {
+        match page_range(addr, len) {
+            Some(range) => range,
+            None => return -1,
+        }
+    };
+    if !space.is_free(start, end) {
+        return -1;
+    }
+    let area = MapArea::new(start.to_addr(), end.to_addr(), MapType::Lazy, perm);
+    if space.push(area, None).is_none() {
+        return -1;
+    }
+    start.to_addr().0 as isize
+}
+
+// unmap pages of [addr, addr + len), partly covered areas are split
+pub fn sys_munmap(addr: usize, len: usize) -> isize {
+    let (start, end) = match page_range(addr, len) {
+        Some(range) => range,
+        None => return -1,
+    };
+    let task = current_task().unwrap();
+    let user_space = task.user_space();
+    let mut space = user_space.lock();
+    if space.unmap_range(start, end) {
+        0
+    } else {
+        -1
+    }
+}
+
+// change permission of mapped pages of [addr, addr + len)
+pub fn sys_mprotect(addr: usize, len: usize, prot: usize) -> isize {
+    let (start, end, perm) = match (page_range(addr, len), prot_to_perm(prot)) {
+        (Some((start, end)), Some(perm)) => (start, end, perm),
+        _ => return -1,
+    };
+    let task = current_task().unwrap();
+    let user_space = task.user_space();
+    let mut space = user_space.lock();
+    if space.protect_range(start, end, perm) {
+        0
+    } else {
+        -1
+    }
+}
+
+// move the end of the heap of current task to addr, 0 only queries it
+// return the new end, or -1 if it cannot be moved there
+pub fn sys_brk(addr: usize) -> isize {
+    let task = current_task().unwrap();
+    let user_space = task.user_space();
+    let mut space = user_space.lock();
+    if addr == 0 || space.set_brk(addr) {
+        space.brk() as isize
+    } else {
+        -1
+    }
+}
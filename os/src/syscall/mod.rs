@@ -2,25 +2,51 @@ pub mod id;
 mod fs;
 mod proc;
 mod ipc;
+mod mm;
 use id::*;
 use fs::*;
 use proc::*;
 use ipc::*;
+use mm::*;
+pub use proc::exit_group;
 use crate::{config::CLOCK_FREQ, time::get_time};
 
-pub fn syscall(id: usize, args: [usize; 4]) -> isize {
+pub fn syscall(id: usize, args: [usize; 6]) -> isize {
     match id {
         SYSCALL_WRITE => sys_write(args[0], args[1] as *const u8, args[2]),
         SYSCALL_EXIT => sys_exit(args[0] as i32),
         SYSCALL_YIELD => sys_yield(),
-        SYSCALL_RECV => sys_recv(args[0], args[1]),
-        SYSCALL_SENDRECV => sys_sendrecv(args[0], args[1], args[2], args[3]),
+        SYSCALL_RECV => sys_recv(args[0], args[1], args[2]),
+        SYSCALL_REPLY => sys_reply(args[0], args[1], args[2], args[3]),
         SYSCALL_FORK => sys_fork(),
         SYSCALL_EXEC => sys_exec(args[0] as *const u8, args[1]),
         SYSCALL_WAITPID => sys_waitpid(args[0] as isize, args[1] as *mut i32),
         SYSCALL_READ => sys_read(args[0], args[1] as *mut u8, args[2]),
         SYSCALL_GETPID => sys_getpid(),
         SYSCALL_GETTIME => (get_time() / (CLOCK_FREQ / 1000)) as isize,
+        SYSCALL_CALL => sys_call(args[0], args[1], args[2], args[3], args[4], args[5]),
+        SYSCALL_ENDPOINT_CREATE => sys_endpoint_create(),
+        SYSCALL_SEND => sys_send(args[0], args[1], args[2], args[3]),
+        SYSCALL_TRY_RECV => sys_try_recv(args[0], args[1], args[2]),
+        SYSCALL_CAP_DUP => sys_cap_dup(args[0], args[1]),
+        SYSCALL_CAP_DROP => sys_cap_drop(args[0]),
+        SYSCALL_CAP_TASK => sys_cap_task(),
+        SYSCALL_CAP_MEMORY => sys_cap_memory(args[0], args[1]),
+        SYSCALL_CAP_IDENTIFY => sys_cap_identify(args[0]),
+        SYSCALL_MEM_MAP => sys_mem_map(args[0], args[1]),
+        SYSCALL_MEM_UNMAP => sys_mem_unmap(args[0]),
+        SYSCALL_IPC_TIMEOUT => sys_ipc_timeout(args[0]),
+        SYSCALL_MMAP => sys_mmap(args[0], args[1], args[2]),
+        SYSCALL_MUNMAP => sys_munmap(args[0], args[1]),
+        SYSCALL_MPROTECT => sys_mprotect(args[0], args[1], args[2]),
+        SYSCALL_BRK => sys_brk(args[0]),
+        SYSCALL_GET_PRIORITY => sys_get_priority(args[0]),
+        SYSCALL_SET_PRIORITY => sys_set_priority(args[0], args[1] as isize),
+        SYSCALL_THREAD_CREATE => sys_thread_create(args[0], args[1], args[2]),
+        SYSCALL_THREAD_EXIT => sys_thread_exit(args[0] as i32),
+        SYSCALL_THREAD_JOIN => sys_thread_join(args[0], args[1] as *mut i32),
+        SYSCALL_GETTID => sys_gettid(),
+        SYSCALL_SLEEP => sys_sleep(args[0]),
         _ => {
             panic!("Unsupported syscall id: {}", id);
         }
@@ -1,14 +1,44 @@
-use alloc::{string::String, vec};
-use crate::{ipc::RPC_BUFFER, loader::get_app_data_by_name, mm::page_table::{get_user_byte_buffer, translate_refmut}, println, task::{add_task, exit_current_and_run_next, processor::{current_task, current_user_satp, take_current_task}, recycle_id, rpc_call, show_task_frames, suspend_current_and_run_next}};
+use alloc::{string::String, sync::Arc, vec};
+use crate::{config::{APP_NAME_MAX, CLOCK_FREQ, RPC_TIMEOUT}, ipc::{rpc_call, rpc_send, PROCESS_MANAGER_EP}, loader::{get_app_data_by_name, is_service}, mm::user_ptr::{UserPtr, UserSlice}, task::{add_task, block_current_and_run_next, exit_current_and_run_next, exit_current_thread, find_task, kill_current_threads, processor::current_task, recycle_id, scheduler::{Priority, NICE_MAX, NICE_MIN}, suspend_current_and_run_next, task::TaskControlBlock, update_task, wake_task}, time::{add_timer, get_time, remove_timer}};
 use super::id::*;
 
-const PROCESS_MANAGER_ID: usize = 1;
-
+// any thread ends the whole process, as does a fatal trap
 pub fn sys_exit(exit_code: i32) -> ! {
+    exit_group(exit_code)
+}
+
+// end the whole process from any thread
+// another thread kills the main thread, which exits the process on its way back to user mode
+pub fn exit_group(exit_code: i32) -> ! {
     let task = current_task().unwrap();
-    let id = task.taskid.0;
+    if task.is_main_thread() {
+        drop(task);
+        exit_process(exit_code);
+    }
+    if let Some(main_thread) = task.main_thread() {
+        let mut main_inner = main_thread.inner.lock();
+        // an exiting main thread already ends this one
+        if main_inner.exit_code.is_none() && !main_inner.killed {
+            main_inner.killed = true;
+            main_inner.kill_code = exit_code;
+        }
+        drop(main_inner);
+        wake_task(main_thread);
+    }
     drop(task);
-    rpc_call(PROCESS_MANAGER_ID, vec![SYSCALL_EXIT, id, exit_code as usize]);
+    exit_current_thread(exit_code)
+}
+
+// called by the main thread, it ends the other threads first and tells process_manager
+fn exit_process(exit_code: i32) -> ! {
+    let id = current_task().unwrap().taskid.0;
+    kill_current_threads(exit_code);
+    let args = vec![SYSCALL_EXIT, id, exit_code as usize];
+    // notify process_manager without waiting, unless its queue is full
+    // the task exits anyway if process_manager is gone or does not answer
+    if rpc_send(&PROCESS_MANAGER_EP, args.clone()).is_err() {
+        let _ = rpc_call(&PROCESS_MANAGER_EP, args, Some(RPC_TIMEOUT));
+    }
     exit_current_and_run_next();
     panic!("Unreachable in sys_exit!");
 }
@@ -19,16 +49,43 @@ pub fn sys_yield() -> isize {
     0
 }
 
+// block the current task for at least ms milliseconds
+// it is woken on the first timer interrupt or idle hart after the deadline
+pub fn sys_sleep(ms: usize) -> isize {
+    let task = current_task().unwrap();
+    // a huge ms saturates to a deadline that never comes instead of wrapping into the past
+    let deadline = get_time().saturating_add(ms.saturating_mul(CLOCK_FREQ / 1000));
+    add_timer(deadline, task.clone());
+    while get_time() < deadline && !task.inner.lock().killed {
+        block_current_and_run_next();
+    }
+    remove_timer(deadline, &task);
+    0
+}
+
+// threads of a process share its pid
 pub fn sys_getpid() -> isize {
+    current_task().unwrap().tgid as isize
+}
+
+pub fn sys_gettid() -> isize {
     current_task().unwrap().taskid.0 as isize
 }
 
 pub fn sys_fork() -> isize {
     let current_task = current_task().unwrap();
-    let new_task = current_task.fork();
-    let current_id = current_task.taskid.0;
+    let new_task = match current_task.fork() {
+        Some(task) => task,
+        None => return -1,
+    };
+    let current_id = current_task.tgid;
     let new_id = new_task.taskid.0;
-    rpc_call(PROCESS_MANAGER_ID, vec![SYSCALL_FORK, current_id, new_id]);
+    // the child never runs if process_manager does not record it
+    // no timeout: a late reply could record a child that was already dropped here
+    if rpc_call(&PROCESS_MANAGER_EP, vec![SYSCALL_FORK, current_id, new_id], None).is_err() {
+        recycle_id(new_id);
+        return -1;
+    }
     let trap_cx = new_task.get_trap_cx();
     trap_cx.x[10] = 0;
     add_task(new_task);
@@ -36,16 +93,26 @@ pub fn sys_fork() -> isize {
 } 
 
 pub fn sys_exec(path: *const u8, len: usize) -> isize {
-    let satp = current_user_satp();
-    let name_vec = get_user_byte_buffer(satp, path, len);
-    let name = String::from_utf8(name_vec).unwrap();
-    if name == "process_manager" {
+    if len > APP_NAME_MAX {
+        return -1;
+    }
+    let name = match UserSlice::new(path as usize, len).read() {
+        Ok(name_vec) => match String::from_utf8(name_vec) {
+            Ok(name) => name,
+            Err(_) => return -1,
+        },
+        Err(err) => return err.code(),
+    };
+    if is_service(name.as_str()) {
         return -1;
     }
     if let Some(data) = get_app_data_by_name(name.as_str()) {
         let task = current_task().unwrap();
-        task.exec(data);
-        0
+        if task.exec(data) {
+            0
+        } else {
+            -1
+        }
     } else {
         -1
     }
@@ -53,15 +120,128 @@ pub fn sys_exec(path: *const u8, len: usize) -> isize {
 
 pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
     let task = current_task().unwrap();
-    let id = task.taskid.0;
-    rpc_call(PROCESS_MANAGER_ID, vec![SYSCALL_WAITPID, id, pid as usize]);
-    let rpc = RPC_BUFFER.lock();
-    let ret = rpc.data[0] as isize;
+    let id = task.tgid;
+    // process_manager holds the request until a child exits
+    let reply = match rpc_call(&PROCESS_MANAGER_EP, vec![SYSCALL_WAITPID, id, pid as usize], None) {
+        Ok(reply) => reply,
+        Err(_) => return -1,
+    };
+    let ret = reply[0] as isize;
     if ret > 0 {
-        let satp = current_user_satp();
-        let exit_code = translate_refmut(satp, exit_code_ptr);
-        *exit_code = rpc.data[1] as i32;
         recycle_id(ret as usize);
+        // the child is reaped even if its exit code cannot be stored
+        let exit_code = UserPtr::new(exit_code_ptr);
+        if !exit_code.is_null() {
+            if let Err(err) = exit_code.write(reply[1] as i32) {
+                return err.code();
+            }
+        }
     }
     ret
 }
+
+// pid 0 is the current task
+fn priority_target(pid: usize) -> Option<Arc<TaskControlBlock>> {
+    if pid == 0 {
+        current_task()
+    } else {
+        find_task(pid)
+    }
+}
+
+// return 20 - nice as nice itself may be negative, -1 if there is no such task
+pub fn sys_get_priority(pid: usize) -> isize {
+    match priority_target(pid) {
+        Some(task) => 20 - task.inner.lock().sched.nice,
+        None => -1,
+    }
+}
+
+// a task may lower its own priority, only services and the parent may raise it or touch other tasks
+pub fn sys_set_priority(pid: usize, nice: isize) -> isize {
+    if !(NICE_MIN..=NICE_MAX).contains(&nice) {
+        return -1;
+    }
+    let current = current_task().unwrap();
+    let task = match priority_target(pid) {
+        Some(task) => task,
+        None => return -1,
+    };
+    let privileged = matches!(current.priority, Priority::SERVICE) || task.is_child_of(&current);
+    let mut inner = task.inner.lock();
+    if !privileged && (!Arc::ptr_eq(&task, &current) || nice < inner.sched.nice) {
+        return -1;
+    }
+    inner.sched.set_nice(task.priority, nice);
+    drop(inner);
+    update_task(&task);
+    0
+}
+
+// start a thread running entry with arg0 and arg1 sharing the address space and handles of the current task
+// return its tid, -1 if frames or thread slots run out or the process is exiting
+pub fn sys_thread_create(entry: usize, arg0: usize, arg1: usize) -> isize {
+    let task = current_task().unwrap();
+    match task.new_thread(entry, [arg0, arg1]) {
+        Some(thread) => {
+            let tid = thread.taskid.0 as isize;
+            add_task(thread);
+            tid
+        }
+        None => -1,
+    }
+}
+
+// a thread other than the main one only exits itself
+// the main thread exits the process like sys_exit
+pub fn sys_thread_exit(exit_code: i32) -> ! {
+    if !current_task().unwrap().is_main_thread() {
+        exit_current_thread(exit_code);
+    }
+    exit_process(exit_code)
+}
+
+// block until thread tid of the same process exits and reap it
+// return tid, -1 if it is not another thread of the process, someone else joins it
+// or the process exits meanwhile
+pub fn sys_thread_join(tid: usize, exit_code_ptr: *mut i32) -> isize {
+    let current = current_task().unwrap();
+    let thread = match find_task(tid) {
+        Some(thread) if thread.tgid == current.tgid && !thread.is_main_thread() && !Arc::ptr_eq(&thread, &current) => thread,
+        _ => return -1,
+    };
+    let exit_code = loop {
+        if current.inner.lock().killed {
+            return -1;
+        }
+        let mut inner = thread.inner.lock();
+        if let Some(exit_code) = inner.exit_code {
+            break exit_code;
+        }
+        if inner.joiner.as_ref().is_some_and(|joiner| !Arc::ptr_eq(joiner, &current)) {
+            return -1;
+        }
+        inner.joiner = Some(current.clone());
+        drop(inner);
+        block_current_and_run_next();
+    };
+    // the exiting main thread reaps the threads it has taken
+    let reaped = current.main_thread().is_some_and(|main_thread| {
+        let mut main_inner = main_thread.inner.lock();
+        let count = main_inner.threads.len();
+        main_inner.threads.retain(|t| !Arc::ptr_eq(t, &thread));
+        main_inner.threads.len() < count
+    });
+    if !reaped {
+        return -1;
+    }
+    recycle_id(tid);
+    // the thread is reaped even if its exit code cannot be stored
+    let exit_code_ptr = UserPtr::new(exit_code_ptr);
+    if !exit_code_ptr.is_null() {
+        if let Err(err) = exit_code_ptr.write(exit_code) {
+            return err.code();
+        }
+    }
+    tid as isize
+}
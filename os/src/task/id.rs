@@ -1,7 +1,7 @@
 use alloc::vec::Vec;
 use lazy_static::lazy_static;
 use spin::SpinLock;
-use crate::{config::*, mm::{address::VirtAddr, address_space::{MapArea, MapType, KERNEL_SPACE}, page_table::PTEFlags}};
+use crate::{config::{KERNEL_STACK_SIZE, PAGE_SIZE, TRAMPOLINE_ADDR}, mm::{address::VirtAddr, address_space::{MapArea, MapType, KERNEL_SPACE}, page_table::PTEFlags}};
 
 pub struct TaskidAllocator {
     current: usize,
@@ -53,15 +53,18 @@ pub fn kernel_stack_pos(app_id: usize) -> (usize, usize) {
     (bottom, top)
 }
 
+// kernel stacks sit below the trampoline with an unmapped guard page each
+// other harts drop stale translations of them when they activate KERNEL_SPACE before running a task
 pub struct KernelStack {
     taskid: usize
 }
 
 impl KernelStack {
-    pub fn new(id_tracker: &IdTracker) -> Self {
+    // return none if frames run out
+    pub fn new(id_tracker: &IdTracker) -> Option<Self> {
         let id = id_tracker.0;
         let (bottom, top) = kernel_stack_pos(id);
-         KERNEL_SPACE.lock().push(
+        KERNEL_SPACE.lock().push(
             MapArea::new(
                 VirtAddr(bottom),
                 VirtAddr(top),
@@ -69,10 +72,10 @@ impl KernelStack {
                 PTEFlags::R | PTEFlags::W,
             ),
             None
-         );
-        KernelStack {
+        )?;
+        Some(KernelStack {
             taskid: id_tracker.0
-        }
+        })
     }
 
     pub fn get_top(&self) -> usize {
@@ -1,10 +1,11 @@
-use alloc::{sync::Arc, vec::Vec};
+use alloc::sync::Arc;
 use lazy_static::lazy_static;
-use crate::{ipc::RPC_BUFFER, loader::get_app_data_by_name};
-use self::{context::TaskContext, processor::{current_task, schedule, take_current_task}, scheduler::SCHEDULER, task::{TaskControlBlock, TaskStatus}};
+use spin::SpinLock;
+use crate::{cap::{CapObject, CapRights, CapTable, Capability}, ipc::{NAME_SERVER_EP, PROCESS_MANAGER_EP}, loader::get_app_data_by_name};
+use self::{context::TaskContext, processor::{current_task, hart_id, schedule, take_current_task}, scheduler::{SchedPolicy, SCHEDULER}, task::{TaskControlBlock, TaskStatus}};
 mod context;
 pub mod task; 
-mod scheduler;
+pub mod scheduler;
 mod switch;
 mod id;
 pub mod processor;
@@ -12,17 +13,29 @@ pub mod processor;
 lazy_static!{
     pub static ref PROCESS_MANAGER: Arc<TaskControlBlock> = Arc::new(TaskControlBlock::new(
         get_app_data_by_name("process_manager").unwrap(), scheduler::Priority::SERVICE
-    ));
+    ).unwrap());
+    pub static ref NAME_SERVER: Arc<TaskControlBlock> = Arc::new(TaskControlBlock::new(
+        get_app_data_by_name("name_server").unwrap(), scheduler::Priority::SERVICE
+    ).unwrap());
     pub static ref INIT: Arc<TaskControlBlock> = Arc::new(TaskControlBlock::new(
         get_app_data_by_name("init").unwrap(), scheduler::Priority::USER
-    ));
+    ).unwrap());
 }
 
+// services receive on their endpoints through handle 0
 pub fn add_service() {
+    let cap = Capability::new(CapObject::Endpoint(PROCESS_MANAGER_EP.clone()), CapRights::RECV);
+    PROCESS_MANAGER.inner.lock().caps.lock().insert(cap);
     add_task(PROCESS_MANAGER.clone());
+    let cap = Capability::new(CapObject::Endpoint(NAME_SERVER_EP.clone()), CapRights::RECV);
+    NAME_SERVER.inner.lock().caps.lock().insert(cap);
+    add_task(NAME_SERVER.clone());
 }
 
+// init and its descendants reach name_server through handle 0
 pub fn add_init() {
+    let cap = Capability::new(CapObject::Endpoint(NAME_SERVER_EP.clone()), CapRights::SEND);
+    INIT.inner.lock().caps.lock().insert(cap);
     add_task(INIT.clone());
 }
 
@@ -34,12 +47,27 @@ pub fn push_task(task: Arc<TaskControlBlock>) {
     SCHEDULER.lock().push_task(task);
 }
 
+pub fn requeue_task(task: Arc<TaskControlBlock>, preempted: bool) {
+    SCHEDULER.lock().requeue_task(hart_id(), task, preempted);
+}
+
+#[allow(unused)]
+// switch the scheduling policy, ready tasks are moved over
+pub fn set_sched_policy<P: SchedPolicy + 'static>() {
+    SCHEDULER.lock().set_policy::<P>();
+}
+
 pub fn fetch_task() -> Option<Arc<TaskControlBlock>> {
-    SCHEDULER.lock().fetch_task()
+    SCHEDULER.lock().fetch_task(hart_id())
+}
+
+pub fn find_task(id: usize) -> Option<Arc<TaskControlBlock>> {
+    SCHEDULER.lock().find_task(id)
 }
 
-pub fn id2task(id: usize) -> Option<Arc<TaskControlBlock>> {
-    SCHEDULER.lock().id2task(id)
+// move task in the ready queues after its SchedInfo changed
+pub fn update_task(task: &Arc<TaskControlBlock>) {
+    SCHEDULER.lock().update_task(task);
 }
 
 pub fn recycle_id(id: usize) {
@@ -52,49 +80,113 @@ pub fn show_task_frames() {
     SCHEDULER.lock().show_task_frames();
 }
 
-pub fn rpc_call(calleeid: usize, args: Vec<usize>) {
-    let mut rpc = RPC_BUFFER.lock();
-    // save current caller
-    let caller = rpc.caller.take();
-    let current = current_task().unwrap();
-    // let caller be current task
-    rpc.sender = current.taskid.0;
-    rpc.caller = Some(current);
-    rpc.callee = id2task(calleeid);
-    rpc.data = args;
-    drop(rpc);
-    block_current_and_run_next();
-    // now back to current task
-    let mut rpc = RPC_BUFFER.lock();
-    rpc.caller = caller;
+// make a blocked task ready again
+// a task still running may be about to block, it keeps the wakeup for then
+pub fn wake_task(task: Arc<TaskControlBlock>) {
+    let mut inner = task.inner.lock();
+    match inner.task_status {
+        TaskStatus::Block => {
+            inner.task_status = TaskStatus::Ready;
+            drop(inner);
+            push_task(task);
+        }
+        TaskStatus::Running => inner.wakeup = true,
+        _ => {}
+    }
 }
 
+// the current task gives up the cpu on its own
 pub fn suspend_current_and_run_next() {
+    requeue_current_and_run_next(false);
+}
+
+// the time slice of the current task ran out
+pub fn preempt_current_and_run_next() {
+    requeue_current_and_run_next(true);
+}
+
+fn requeue_current_and_run_next(preempted: bool) {
     let task = take_current_task().unwrap();
     let mut inner = task.inner.lock();
     let task_cx_ptr = &mut inner.task_cx as *mut TaskContext;
     inner.task_status = TaskStatus::Ready;
     drop(inner);
-    if RPC_BUFFER.lock().callee.is_none() {
-        push_task(task);
-    }
+    requeue_task(task, preempted);
     schedule(task_cx_ptr);
 }
 
+// callers recheck what they wait for, a wakeup that came early returns at once
 pub fn block_current_and_run_next() {
-    let task = take_current_task().unwrap();
+    let task = current_task().unwrap();
     let mut inner = task.inner.lock();
+    if core::mem::take(&mut inner.wakeup) {
+        return;
+    }
     let task_cx_ptr = &mut inner.task_cx as *mut TaskContext;
     inner.task_status = TaskStatus::Block;
     drop(inner);
+    drop(task);
+    take_current_task();
     schedule(task_cx_ptr);
 }
 
+// whether the current thread should stop waiting and exit
+pub fn current_killed() -> bool {
+    current_task().unwrap().inner.lock().killed
+}
+
+// called by the main thread exiting the process, other threads are killed and reaped
+// they exit on their way back to user mode, blocked ones are woken first
+pub fn kill_current_threads(exit_code: i32) {
+    let task = current_task().unwrap();
+    let threads = {
+        let mut inner = task.inner.lock();
+        inner.exit_code = Some(exit_code);
+        core::mem::take(&mut inner.threads)
+    };
+    for thread in threads.iter() {
+        thread.inner.lock().killed = true;
+        wake_task(thread.clone());
+    }
+    for thread in threads {
+        loop {
+            let mut inner = thread.inner.lock();
+            if inner.exit_code.is_some() {
+                break;
+            }
+            inner.joiner = Some(task.clone());
+            drop(inner);
+            block_current_and_run_next();
+        }
+        recycle_id(thread.taskid.0);
+    }
+}
+
+// release the stack and trap context slot of the current thread and keep
+// exit_code until the thread is joined, a thread joining it is woken
+pub fn exit_current_thread(exit_code: i32) -> ! {
+    let task = current_task().unwrap();
+    let mut inner = task.inner.lock();
+    inner.user_space.lock().dealloc_thread(task.trap_slot);
+    inner.exit_code = Some(exit_code);
+    let joiner = inner.joiner.take();
+    drop(inner);
+    drop(task);
+    if let Some(joiner) = joiner {
+        wake_task(joiner);
+    }
+    exit_current_and_run_next();
+    unreachable!()
+}
+
 pub fn exit_current_and_run_next() {
     let task = take_current_task().unwrap();
     let mut inner = task.inner.lock();
     inner.task_status = TaskStatus::Exit;
+    // the last thread of the process releases its capabilities, closing endpoints only it received on
+    let caps = core::mem::replace(&mut inner.caps, Arc::new(SpinLock::new(CapTable::new())));
     drop(inner);
+    drop(caps);
     drop(task);
     let mut _unused = TaskContext::new(0, 0);
     schedule(&mut _unused as *mut _);
@@ -1,7 +1,9 @@
-use alloc::sync::Arc;
+use core::arch::asm;
+use core::sync::atomic::Ordering;
+use alloc::{sync::Arc, vec::Vec};
 use lazy_static::lazy_static;
 use spin::SpinLock;
-use crate::trap::context::TrapContext;
+use crate::{config::MAX_HARTS, mm::address_space::KERNEL_SPACE, time::check_timers, trap::context::TrapContext};
 
 use super::{context::TaskContext, fetch_task, switch::__switch, task::{TaskControlBlock, TaskStatus}};
 
@@ -31,38 +33,71 @@ impl Processor {
 }
 
 lazy_static! {
-    pub static ref PROCESSOR: SpinLock<Processor> = SpinLock::new(Processor::new());
+    // PROCESSORS[i] belongs to hart i
+    pub static ref PROCESSORS: Vec<SpinLock<Processor>> = (0..MAX_HARTS)
+        .map(|_| SpinLock::new(Processor::new()))
+        .collect();
+}
+
+// the kernel keeps the hart id in tp
+pub fn hart_id() -> usize {
+    let id;
+    unsafe {
+        asm!("mv {}, tp", out(reg) id);
+    }
+    id
+}
+
+fn processor() -> &'static SpinLock<Processor> {
+    &PROCESSORS[hart_id()]
 }
 
 pub fn run_tasks() {
     loop {
-        let mut processor = PROCESSOR.lock();
+        let mut processor = processor().lock();
         if let Some(task) = fetch_task() {
+            // the task may still be switching away on another hart
+            while task.on_cpu.load(Ordering::Acquire) {
+                core::hint::spin_loop();
+            }
+            task.on_cpu.store(true, Ordering::Relaxed);
             let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
             let mut inner = task.inner.lock();
             let next_task_cx_ptr = &inner.task_cx as *const TaskContext;
             inner.task_status = TaskStatus::Running;
             drop(inner);
-            processor.current = Some(task);
+            processor.current = Some(task.clone());
             drop(processor);
+            // the kernel stack of the task may have been mapped by another hart
+            KERNEL_SPACE.lock().root_table.activate(hart_id());
             unsafe {
                 __switch(idle_task_cx_ptr, next_task_cx_ptr);
             }
+            // its context is saved, other harts may run it now
+            task.on_cpu.store(false, Ordering::Release);
+        } else {
+            // every task is blocked, some may be waiting for a deadline
+            drop(processor);
+            check_timers();
         }
     }
 }
 
 pub fn current_task() -> Option<Arc<TaskControlBlock>> {
-    PROCESSOR.lock().current()
+    processor().lock().current()
 }
 
 pub fn take_current_task() -> Option<Arc<TaskControlBlock>> {
-    PROCESSOR.lock().take_current()
+    processor().lock().take_current()
 }
 
+// satp of the current task, its stale translations on this hart are flushed first
 pub fn current_user_satp() -> usize {
     let task = current_task().unwrap();
-    task.get_user_satp()
+    let user_space = task.user_space();
+    let space = user_space.lock();
+    space.activate(hart_id());
+    space.root_table.get_satp()
 }
 
 pub fn current_trap_cx() -> &'static mut TrapContext {
@@ -72,7 +107,7 @@ pub fn current_trap_cx() -> &'static mut TrapContext {
 }
 
 pub fn schedule(switched_task_cx_ptr: *mut TaskContext) {
-    let mut processor = PROCESSOR.lock();
+    let mut processor = processor().lock();
     let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
     drop(processor);
     unsafe {
@@ -0,0 +1,82 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{super::task::TaskControlBlock, Priority, SchedPolicy};
+
+pub const MLFQ_LEVELS: usize = 3;
+// every task moves back to the top level after this many fetches, so demoted tasks do not starve
+const BOOST_INTERVAL: usize = 256;
+
+// tasks using up their time slice sink a level, tasks yielding or blocking keep theirs
+// services and tasks with negative nice are never demoted
+pub struct MultiLevelFeedback {
+    queue: [VecDeque<Arc<TaskControlBlock>>; MLFQ_LEVELS],
+    fetched: usize,
+}
+
+impl MultiLevelFeedback {
+    fn push(&mut self, task: Arc<TaskControlBlock>) {
+        let level = task.inner.lock().sched.level;
+        self.queue[level].push_back(task);
+    }
+
+    fn boost(&mut self) {
+        let demoted: Vec<_> = self.queue[1..].iter_mut().flat_map(|queue| queue.drain(..)).collect();
+        for task in demoted {
+            let mut inner = task.inner.lock();
+            inner.sched.level = inner.sched.base_level();
+            drop(inner);
+            self.push(task);
+        }
+    }
+}
+
+impl SchedPolicy for MultiLevelFeedback {
+    fn new() -> Self {
+        Self {
+            queue: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            fetched: 0,
+        }
+    }
+
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        self.push(task);
+    }
+
+    fn requeue(&mut self, task: Arc<TaskControlBlock>, preempted: bool) {
+        let mut inner = task.inner.lock();
+        if preempted && matches!(task.priority, Priority::USER) && inner.sched.nice >= 0 {
+            inner.sched.level = (inner.sched.level + 1).min(MLFQ_LEVELS - 1);
+        }
+        drop(inner);
+        self.push(task);
+    }
+
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.fetched += 1;
+        if self.fetched % BOOST_INTERVAL == 0 {
+            self.boost();
+        }
+        for queue in self.queue.iter_mut() {
+            if let Some(task) = queue.pop_front() {
+                return Some(task);
+            }
+        }
+        None
+    }
+
+    fn drain(&mut self) -> Vec<Arc<TaskControlBlock>> {
+        self.queue.iter_mut().flat_map(|queue| queue.drain(..)).collect()
+    }
+
+    fn update(&mut self, task: &Arc<TaskControlBlock>) {
+        for queue in self.queue.iter_mut() {
+            if let Some(i) = queue.iter().position(|queued| Arc::ptr_eq(queued, task)) {
+                queue.remove(i);
+                self.push(task.clone());
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::SpinLock;
+
+use crate::{config::MAX_HARTS, println};
+
+use super::task::TaskControlBlock;
+use self::mlfq::MultiLevelFeedback;
+#[allow(unused)]
+pub use self::{round_robin::RoundRobin, stride::Stride};
+
+mod round_robin;
+mod stride;
+mod mlfq;
+
+#[derive(Clone, Copy)]
+pub enum Priority {
+    SERVICE,
+    USER,
+}
+
+// nice values as in unix, lower runs more
+pub const NICE_MIN: isize = -20;
+pub const NICE_MAX: isize = 19;
+// services get a larger share of tickets than user tasks of the same nice
+const SERVICE_TICKET_SCALE: usize = 8;
+
+// per-task state kept for the scheduling policies
+pub struct SchedInfo {
+    pub nice: isize,
+    pub tickets: usize,
+    // stride scheduling runs the task with the lowest pass
+    pub pass: usize,
+    // queue level of the multi-level feedback policy
+    pub level: usize,
+}
+
+impl SchedInfo {
+    pub fn new(priority: Priority) -> Self {
+        Self {
+            nice: 0,
+            tickets: Self::tickets_of(priority, 0),
+            pass: 0,
+            level: 0,
+        }
+    }
+
+    // a forked child starts where its parent is
+    pub fn fork(&self) -> Self {
+        Self {
+            nice: self.nice,
+            tickets: self.tickets,
+            pass: self.pass,
+            level: self.level,
+        }
+    }
+
+    // nice -20 gets 40 tickets and nice 19 gets 1
+    fn tickets_of(priority: Priority, nice: isize) -> usize {
+        let tickets = (NICE_MAX + 1 - nice) as usize;
+        match priority {
+            Priority::SERVICE => tickets * SERVICE_TICKET_SCALE,
+            Priority::USER => tickets,
+        }
+    }
+
+    // the highest queue level of the multi-level feedback policy, positive nice starts lower
+    pub fn base_level(&self) -> usize {
+        if self.nice > 0 {
+            1
+        } else {
+            0
+        }
+    }
+
+    // a task sinks to its new base level but never rises, so changing nice
+    // cannot be used to climb back to the top queue
+    pub fn set_nice(&mut self, priority: Priority, nice: isize) {
+        let base = self.base_level();
+        self.nice = nice;
+        self.tickets = Self::tickets_of(priority, nice);
+        if self.base_level() != base {
+            self.level = self.level.max(self.base_level());
+        }
+    }
+}
+
+// decides which ready task runs next
+pub trait SchedPolicy: Send {
+    fn new() -> Self where Self: Sized;
+    // a new task or a task that became ready again after blocking
+    fn add(&mut self, task: Arc<TaskControlBlock>);
+    // the running task gave up the cpu, preempted if its time slice ran out
+    fn requeue(&mut self, task: Arc<TaskControlBlock>, preempted: bool);
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>>;
+    // take out every ready task when switching to another policy
+    fn drain(&mut self) -> Vec<Arc<TaskControlBlock>>;
+    // the SchedInfo of task changed, move it to its new place if it is queued
+    fn update(&mut self, _task: &Arc<TaskControlBlock>) {}
+}
+
+// the policy the kernel boots with
+type DefaultPolicy = MultiLevelFeedback;
+
+// every hart has its own ready queue under the policy, idle harts steal from the busiest one
+pub struct Scheduler {
+    policies: Vec<Box<dyn SchedPolicy>>,
+    // number of ready tasks queued on each hart
+    loads: Vec<usize>,
+    id2task: BTreeMap<usize, Arc<TaskControlBlock>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            policies: (0..MAX_HARTS).map(|_| Box::new(DefaultPolicy::new()) as Box<dyn SchedPolicy>).collect(),
+            loads: vec![0; MAX_HARTS],
+            id2task: BTreeMap::new(),
+        }
+    }
+
+    // ready tasks move over to the new policy, keeping their harts
+    pub fn set_policy<P: SchedPolicy + 'static>(&mut self) {
+        for old in self.policies.iter_mut() {
+            let mut policy: Box<dyn SchedPolicy> = Box::new(P::new());
+            for task in old.drain() {
+                policy.add(task);
+            }
+            *old = policy;
+        }
+    }
+
+    // new and woken tasks go to the least loaded hart
+    fn least_loaded(&self) -> usize {
+        (0..MAX_HARTS).min_by_key(|&hart| self.loads[hart]).unwrap()
+    }
+
+    fn busiest(&self) -> usize {
+        (0..MAX_HARTS).max_by_key(|&hart| self.loads[hart]).unwrap()
+    }
+
+    pub fn add_task(&mut self, task: Arc<TaskControlBlock>) {
+        self.push_task(task.clone());
+        self.id2task.insert(task.taskid.0, task);
+    }
+
+    pub fn push_task(&mut self, task: Arc<TaskControlBlock>) {
+        let hart = self.least_loaded();
+        self.policies[hart].add(task);
+        self.loads[hart] += 1;
+    }
+
+    // a task giving up the cpu stays on the hart it ran on
+    pub fn requeue_task(&mut self, hart: usize, task: Arc<TaskControlBlock>, preempted: bool) {
+        self.policies[hart].requeue(task, preempted);
+        self.loads[hart] += 1;
+    }
+
+    pub fn update_task(&mut self, task: &Arc<TaskControlBlock>) {
+        for policy in self.policies.iter_mut() {
+            policy.update(task);
+        }
+    }
+
+    pub fn find_task(&self, id: usize) -> Option<Arc<TaskControlBlock>> {
+        self.id2task.get(&id).cloned()
+    }
+
+    // run the own queue first, otherwise steal from the busiest hart
+    pub fn fetch_task(&mut self, hart: usize) -> Option<Arc<TaskControlBlock>> {
+        let hart = if self.loads[hart] > 0 { hart } else { self.busiest() };
+        let task = self.policies[hart].fetch()?;
+        self.loads[hart] -= 1;
+        Some(task)
+    }
+
+    pub fn recycle_id(&mut self, id: usize) {
+        self.id2task.remove(&id);
+    }
+
+    pub fn show_task_frames(&self) {
+        for task in self.id2task.values() {
+            println!("task {} frames:", task.taskid.0);
+            task.user_space().lock().root_table.show_frames();
+        }
+    }
+}
+
+lazy_static!{
+    pub static ref SCHEDULER: SpinLock<Scheduler> = SpinLock::new(Scheduler::new());
+}
@@ -0,0 +1,39 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{super::task::TaskControlBlock, SchedPolicy};
+
+// one queue per priority, services always run before user tasks
+pub struct RoundRobin {
+    queue: [VecDeque<Arc<TaskControlBlock>>; 2],
+}
+
+impl SchedPolicy for RoundRobin {
+    fn new() -> Self {
+        Self {
+            queue: [VecDeque::new(), VecDeque::new()],
+        }
+    }
+
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        self.queue[task.priority as usize].push_back(task);
+    }
+
+    fn requeue(&mut self, task: Arc<TaskControlBlock>, _preempted: bool) {
+        self.add(task);
+    }
+
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        for queue in self.queue.iter_mut() {
+            if let Some(task) = queue.pop_front() {
+                return Some(task);
+            }
+        }
+        None
+    }
+
+    fn drain(&mut self) -> Vec<Arc<TaskControlBlock>> {
+        self.queue.iter_mut().flat_map(|queue| queue.drain(..)).collect()
+    }
+}
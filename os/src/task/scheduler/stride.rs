@@ -0,0 +1,60 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use super::{super::task::TaskControlBlock, SchedPolicy};
+
+// a task advances its pass by BIG_STRIDE / tickets every time it runs
+const BIG_STRIDE: usize = 1 << 20;
+
+pub struct Stride {
+    // ready tasks ordered by pass, ties broken by arrival
+    ready: BTreeMap<(usize, usize), Arc<TaskControlBlock>>,
+    seq: usize,
+    // pass of the task fetched last
+    current_pass: usize,
+}
+
+impl Stride {
+    fn push(&mut self, task: Arc<TaskControlBlock>, pass: usize) {
+        self.seq += 1;
+        self.ready.insert((pass, self.seq), task);
+    }
+}
+
+impl SchedPolicy for Stride {
+    fn new() -> Self {
+        Self {
+            ready: BTreeMap::new(),
+            seq: 0,
+            current_pass: 0,
+        }
+    }
+
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        // tasks back from blocking must not make up for the time they slept
+        let mut inner = task.inner.lock();
+        inner.sched.pass = inner.sched.pass.max(self.current_pass);
+        let pass = inner.sched.pass;
+        drop(inner);
+        self.push(task, pass);
+    }
+
+    fn requeue(&mut self, task: Arc<TaskControlBlock>, _preempted: bool) {
+        let mut inner = task.inner.lock();
+        inner.sched.pass += BIG_STRIDE / inner.sched.tickets.max(1);
+        let pass = inner.sched.pass;
+        drop(inner);
+        self.push(task, pass);
+    }
+
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        let ((pass, _), task) = self.ready.pop_first()?;
+        self.current_pass = pass;
+        Some(task)
+    }
+
+    fn drain(&mut self) -> Vec<Arc<TaskControlBlock>> {
+        core::mem::take(&mut self.ready).into_values().collect()
+    }
+}
@@ -1,9 +1,10 @@
-use alloc::sync::Arc;
+use core::sync::atomic::AtomicBool;
+use alloc::{sync::{Arc, Weak}, vec::Vec};
 use riscv::register::sstatus;
 use spin::SpinLock;
 
-use crate::{mm::{address::{PhysPageNum, VirtAddr}, address_space::{AddrSpace, KERNEL_SPACE}}, trap::{context::TrapContext, trap_handler, trap_return}};
-use super::{context::TaskContext, id::{alloc_task_id, IdTracker, KernelStack}, scheduler::Priority};
+use crate::{cap::CapTable, ipc::{IpcError, RpcBuffer}, mm::{address::{PhysPageNum, VirtAddr}, address_space::{trap_cx_addr, AddrSpace, KERNEL_SPACE}}, trap::{context::TrapContext, trap_handler, trap_return}};
+use super::{context::TaskContext, find_task, id::{alloc_task_id, IdTracker, KernelStack}, scheduler::{Priority, SchedInfo}};
 use crate::config::*;
 
 
@@ -17,8 +18,16 @@ pub enum TaskStatus {
 
 pub struct TaskControlBlock {
     pub taskid: IdTracker,
+    // taskid of the main thread, the process id process_manager knows
+    pub tgid: usize,
+    // TRAP_CONTEXT slot in the address space, 0 for the main thread
+    pub trap_slot: usize,
     pub kernel_stack: KernelStack,
     pub priority: Priority,
+    // none for tasks created by the kernel
+    pub parent: Option<Weak<TaskControlBlock>>,
+    // set while a hart runs the task or has not finished switching away from it
+    pub on_cpu: AtomicBool,
     pub inner: SpinLock<TaskControlBlockInner>,
 }
 
@@ -26,38 +35,102 @@ pub struct TaskControlBlock {
 pub struct TaskControlBlockInner {
     pub task_status: TaskStatus,
     pub task_cx: TaskContext,
-    pub user_space: AddrSpace,
+    // shared by all threads of the process
+    pub user_space: Arc<SpinLock<AddrSpace>>,
     pub trap_cx_ppn: PhysPageNum,
+    // result of the ongoing rpc call
+    pub rpc_reply: Option<Result<RpcBuffer, IpcError>>,
+    // timeout of ipc calls and receives in ms, 0 for none
+    pub ipc_timeout: usize,
+    // shared by all threads of the process
+    pub caps: Arc<SpinLock<CapTable>>,
+    pub sched: SchedInfo,
+    // set when a thread exits, until it is joined
+    // set on the main thread when it starts exiting the process
+    pub exit_code: Option<i32>,
+    // thread blocked in join on this thread
+    pub joiner: Option<Arc<TaskControlBlock>>,
+    // woken while still running, the next block returns at once
+    pub wakeup: bool,
+    // other threads of the process not reaped yet, only kept on the main thread
+    pub threads: Vec<Arc<TaskControlBlock>>,
+    // the process is exiting, the thread stops waiting and exits before returning to user mode
+    pub killed: bool,
+    // exit code of the process when another thread ends it
+    pub kill_code: i32,
 }
 
 impl TaskControlBlock {
     pub fn get_trap_cx(&self) -> &'static mut TrapContext {
         self.inner.lock().trap_cx_ppn.get_mut()
     }
-    pub fn get_user_satp(&self) -> usize {
-        self.inner.lock().user_space.root_table.get_satp()
+
+    pub fn user_space(&self) -> Arc<SpinLock<AddrSpace>> {
+        self.inner.lock().user_space.clone()
+    }
+
+    pub fn caps(&self) -> Arc<SpinLock<CapTable>> {
+        self.inner.lock().caps.clone()
+    }
+
+    pub fn is_main_thread(&self) -> bool {
+        self.tgid == self.taskid.0
     }
 
-    pub fn new(elf_data: &[u8], priority: Priority) -> Self {
-        let (user_space, user_stack_top, entry_point) = AddrSpace::new_user(elf_data);
+    pub fn main_thread(self: &Arc<Self>) -> Option<Arc<TaskControlBlock>> {
+        if self.is_main_thread() {
+            Some(self.clone())
+        } else {
+            find_task(self.tgid)
+        }
+    }
+
+    pub fn trap_cx_va(&self) -> usize {
+        trap_cx_addr(self.trap_slot)
+    }
+
+    pub fn is_child_of(&self, task: &Arc<TaskControlBlock>) -> bool {
+        self.parent
+            .as_ref()
+            .and_then(Weak::upgrade)
+            .is_some_and(|parent| Arc::ptr_eq(&parent, task))
+    }
+
+    // return none if frames run out
+    pub fn new(elf_data: &[u8], priority: Priority) -> Option<Self> {
+        let (user_space, user_stack_top, entry_point) = AddrSpace::new_user(elf_data)?;
         let trap_cx_ppn = user_space.root_table
                          .translate_vpn(VirtAddr(TRAP_CONTEXT).floor())
                          .unwrap().ppn();
         let task_status = TaskStatus::Ready;
         // set up kernel stack
         let id_tracker = alloc_task_id();
-        let kernel_stack = KernelStack::new(&id_tracker);
+        let kernel_stack = KernelStack::new(&id_tracker)?;
         let kernel_stack_top = kernel_stack.get_top();
         let inner = SpinLock::new(TaskControlBlockInner{
             task_status,
             task_cx: TaskContext::new(trap_return as usize, kernel_stack_top),
-            user_space,
+            user_space: Arc::new(SpinLock::new(user_space)),
             trap_cx_ppn,
+            rpc_reply: None,
+            ipc_timeout: 0,
+            caps: Arc::new(SpinLock::new(CapTable::new())),
+            sched: SchedInfo::new(priority),
+            exit_code: None,
+            joiner: None,
+            wakeup: false,
+            threads: Vec::new(),
+            killed: false,
+            kill_code: -1,
         });
         let control_block = Self{
+            tgid: id_tracker.0,
+            trap_slot: 0,
             taskid: id_tracker,
             kernel_stack,
             priority,
+            parent: None,
+            on_cpu: AtomicBool::new(false),
             inner
         };
         let trap_cx = control_block.get_trap_cx();
@@ -69,43 +142,138 @@ impl TaskControlBlock {
             trap_handler as usize,
             kernel_stack_top,
         );
-        control_block
+        Some(control_block)
     }   
 
-    pub fn fork(self: &Arc<Self>) -> Arc<TaskControlBlock> {
+    // the child is the main thread of a new process even if a thread forks
+    // return none if frames run out
+    pub fn fork(self: &Arc<Self>) -> Option<Arc<TaskControlBlock>> {
         let parent_inner = self.inner.lock();
         // create child addrspace 
-        let user_space = AddrSpace::from_existed_user(&parent_inner.user_space);
+        let user_space = AddrSpace::from_existed_user(&mut parent_inner.user_space.lock())?;
         let trap_cx_ppn = user_space.root_table
                          .translate_vpn(VirtAddr(TRAP_CONTEXT).floor())
                          .unwrap().ppn();
         // alloc taskid and kernel stack
         let id_tracker = alloc_task_id();
-        let kernel_stack = KernelStack::new(&id_tracker);
+        let kernel_stack = KernelStack::new(&id_tracker)?;
         let kernel_stack_top = kernel_stack.get_top();
+        if self.trap_slot != 0 {
+            // the forking thread becomes the main thread of the child
+            trap_cx_ppn.get_bytes_array().copy_from_slice(parent_inner.trap_cx_ppn.get_bytes_array());
+        }
         let block = Arc::new(TaskControlBlock{
+            tgid: id_tracker.0,
+            trap_slot: 0,
             taskid: id_tracker,
             kernel_stack,
             priority: self.priority,
+            parent: Some(Arc::downgrade(self)),
+            on_cpu: AtomicBool::new(false),
             inner: SpinLock::new(TaskControlBlockInner{
                 task_status: TaskStatus::Ready,
                 task_cx: TaskContext::new(trap_return as usize, kernel_stack_top),
-                user_space,
+                user_space: Arc::new(SpinLock::new(user_space)),
                 trap_cx_ppn,
+                rpc_reply: None,
+                ipc_timeout: parent_inner.ipc_timeout,
+                caps: Arc::new(SpinLock::new(parent_inner.caps.lock().fork())),
+                sched: parent_inner.sched.fork(),
+                exit_code: None,
+                joiner: None,
+                wakeup: false,
+                threads: Vec::new(),
+                killed: false,
+                kill_code: -1,
             })
         });
         let trap_cx = block.get_trap_cx();
         trap_cx.kernel_sp = kernel_stack_top;
-        block
+        Some(block)
+    }
+
+    // a thread shares the address space and the capabilities of the process
+    // it runs entry with args in a0 and a1 on its own user stack
+    // return none if frames or thread slots run out or the process is exiting
+    pub fn new_thread(self: &Arc<Self>, entry: usize, args: [usize; 2]) -> Option<Arc<TaskControlBlock>> {
+        let id_tracker = alloc_task_id();
+        let kernel_stack = KernelStack::new(&id_tracker)?;
+        let kernel_stack_top = kernel_stack.get_top();
+        let creator_inner = self.inner.lock();
+        let user_space = creator_inner.user_space.clone();
+        let mut space = user_space.lock();
+        let (trap_slot, user_stack_top) = space.alloc_thread()?;
+        let trap_cx_ppn = space.translate_vpn(VirtAddr(trap_cx_addr(trap_slot)).floor())
+                         .unwrap().ppn();
+        drop(space);
+        let block = Arc::new(TaskControlBlock{
+            taskid: id_tracker,
+            tgid: self.tgid,
+            trap_slot,
+            kernel_stack,
+            priority: self.priority,
+            parent: Some(Arc::downgrade(self)),
+            on_cpu: AtomicBool::new(false),
+            inner: SpinLock::new(TaskControlBlockInner{
+                task_status: TaskStatus::Ready,
+                task_cx: TaskContext::new(trap_return as usize, kernel_stack_top),
+                user_space,
+                trap_cx_ppn,
+                rpc_reply: None,
+                ipc_timeout: creator_inner.ipc_timeout,
+                caps: creator_inner.caps.clone(),
+                sched: creator_inner.sched.fork(),
+                exit_code: None,
+                joiner: None,
+                wakeup: false,
+                threads: Vec::new(),
+                killed: false,
+                kill_code: -1,
+            })
+        });
+        drop(creator_inner);
+        // the main thread reaps the thread if the process exits first
+        // no thread may be added once it has started exiting
+        let registered = self.main_thread().is_some_and(|main_thread| {
+            let mut main_inner = main_thread.inner.lock();
+            if main_inner.exit_code.is_none() {
+                main_inner.threads.push(block.clone());
+            }
+            main_inner.exit_code.is_none()
+        });
+        if !registered {
+            block.user_space().lock().dealloc_thread(trap_slot);
+            return None;
+        }
+        let trap_cx = block.get_trap_cx();
+        *trap_cx = TrapContext::new(
+            user_stack_top,
+            entry,
+            sstatus::read().bits(),
+            KERNEL_SPACE.lock().root_table.get_satp(),
+            trap_handler as usize,
+            kernel_stack_top,
+        );
+        trap_cx.x[10] = args[0];
+        trap_cx.x[11] = args[1];
+        Some(block)
     }
 
-    pub fn exec(&self, elf_data: &[u8]) {
-        let (user_space, user_sp, entry_point) = AddrSpace::new_user(elf_data);
+    // only a process without other threads, joined or not, may exec
+    // keep the old address space and return false if it may not or frames run out
+    pub fn exec(&self, elf_data: &[u8]) -> bool {
+        if !self.is_main_thread() || Arc::strong_count(&self.inner.lock().user_space) > 1 {
+            return false;
+        }
+        let (user_space, user_sp, entry_point) = match AddrSpace::new_user(elf_data) {
+            Some(result) => result,
+            None => return false,
+        };
         let trap_cx_ppn = user_space.root_table
                          .translate_vpn(VirtAddr(TRAP_CONTEXT).floor())
                          .unwrap().ppn();
         let mut inner = self.inner.lock();
-        inner.user_space = user_space;
+        inner.user_space = Arc::new(SpinLock::new(user_space));
         inner.trap_cx_ppn = trap_cx_ppn;
         let trap_cx = inner.trap_cx_ppn.get_mut();
         *trap_cx = TrapContext::new(
@@ -116,5 +284,6 @@ impl TaskControlBlock {
             trap_handler as usize,
             self.kernel_stack.get_top(),
         );
+        true
     }
 }
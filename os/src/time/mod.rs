@@ -1,19 +1,28 @@
 use core::arch::global_asm;
 use core::ptr::addr_of_mut;
+use alloc::sync::Arc;
 use riscv::register::*;
-use crate::config::{MTIMECMP, TIME_INTERVAL};
+use crate::{config::{MAX_HARTS, MTIMECMP, TIME_INTERVAL}, task::{processor::hart_id, task::TaskControlBlock, wake_task}};
+use self::timer_queue::TIMER_QUEUE;
+
+mod timer_queue;
 
 global_asm!(include_str!("timer_trap.s"));
 
-pub fn set_timer(time: usize) {
+// every hart has its own mtimecmp register
+fn mtime_cmp_addr(hart: usize) -> usize {
+    MTIMECMP + hart * 8
+}
+
+fn set_timer(hart: usize, time: usize) {
     unsafe {
-        let timer = MTIMECMP as *mut usize;
+        let timer = mtime_cmp_addr(hart) as *mut usize;
         *timer = time;
     }
 }
 
 pub fn get_mtime_cmp() -> usize {
-    let cmp = MTIMECMP as *const usize;
+    let cmp = mtime_cmp_addr(hart_id()) as *const usize;
     unsafe { *cmp }
 }
 
@@ -21,20 +30,39 @@ pub fn get_time() -> usize {
     time::read()
 }
 
+// wake task once mtime reaches deadline, the task blocks itself
+pub fn add_timer(deadline: usize, task: Arc<TaskControlBlock>) {
+    TIMER_QUEUE.lock().add(deadline, task);
+}
+
+// cancel the timer added with the same deadline, if it has not expired
+pub fn remove_timer(deadline: usize, task: &Arc<TaskControlBlock>) {
+    TIMER_QUEUE.lock().remove(deadline, task);
+}
+
+// wake tasks whose deadline has passed, checked on timer interrupts and by idle harts
+pub fn check_timers() {
+    let expired = TIMER_QUEUE.lock().expire(get_time());
+    for task in expired {
+        wake_task(task);
+    }
+}
+
 #[link_section = ".bss.stack"]
 #[no_mangle]
-pub static mut TIMER_SCRATCH: [usize; 5] = [0; 5];
+pub static mut TIMER_SCRATCH: [[usize; 5]; MAX_HARTS] = [[0; 5]; MAX_HARTS];
 
+// run in M-mode by every hart
 #[no_mangle]
-pub unsafe fn init_timer() {
-    set_timer(get_time() + TIME_INTERVAL);
+pub unsafe fn init_timer(hart: usize) {
+    set_timer(hart, get_time() + TIME_INTERVAL);
     
-    // TIMER_SCRATCH is stack base for M-mode when handling timer interrupt
-    // TIMER_SCRATCH[3]: address of MTIMECMP
-    // TIMER_SCRATCH[4]: TIME_INTERVAL
-    TIMER_SCRATCH[3] = MTIMECMP;
-    TIMER_SCRATCH[4] = TIME_INTERVAL;
-    mscratch::write(addr_of_mut!(TIMER_SCRATCH) as usize);
+    // TIMER_SCRATCH[hart] is stack base for M-mode when handling timer interrupt
+    // TIMER_SCRATCH[hart][3]: address of MTIMECMP of the hart
+    // TIMER_SCRATCH[hart][4]: TIME_INTERVAL
+    TIMER_SCRATCH[hart][3] = mtime_cmp_addr(hart);
+    TIMER_SCRATCH[hart][4] = TIME_INTERVAL;
+    mscratch::write(addr_of_mut!(TIMER_SCRATCH[hart]) as usize);
 
     // set mtvec
     extern "C" {
@@ -0,0 +1,42 @@
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use lazy_static::lazy_static;
+use spin::SpinLock;
+use crate::task::task::TaskControlBlock;
+
+// tasks blocked until a deadline in mtime ticks, sorted by deadline
+// the task id keeps timers with the same deadline apart
+pub struct TimerQueue {
+    timers: BTreeMap<(usize, usize), Arc<TaskControlBlock>>,
+}
+
+impl TimerQueue {
+    pub fn new() -> Self {
+        Self {
+            timers: BTreeMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, deadline: usize, task: Arc<TaskControlBlock>) {
+        self.timers.insert((deadline, task.taskid.0), task);
+    }
+
+    pub fn remove(&mut self, deadline: usize, task: &Arc<TaskControlBlock>) {
+        self.timers.remove(&(deadline, task.taskid.0));
+    }
+
+    // take out the tasks whose deadline is not after now
+    pub fn expire(&mut self, now: usize) -> Vec<Arc<TaskControlBlock>> {
+        let mut expired = Vec::new();
+        while let Some(entry) = self.timers.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            expired.push(entry.remove());
+        }
+        expired
+    }
+}
+
+lazy_static! {
+    pub static ref TIMER_QUEUE: SpinLock<TimerQueue> = SpinLock::new(TimerQueue::new());
+}
@@ -1,4 +1,4 @@
-# handle timer interrupts in M-mode and delegate it to S-mode
+# handle timer and software interrupts in M-mode and delegate them to S-mode
     .section .text.trap
     .globl _timer_trap
     .align 2
@@ -8,6 +8,12 @@ _timer_trap:
     sd t1, 1*8(sp)
     sd t2, 2*8(sp)
 
+    # mcause 3 with the interrupt bit is a software interrupt
+    csrr t0, mcause
+    andi t0, t0, 0xff
+    li t1, 3
+    beq t0, t1, 1f
+
     # set mtimecmp
     ld t0, 3*8(sp) # mtimecmp
     ld t1, 4*8(sp) # time interval
@@ -18,7 +24,18 @@ _timer_trap:
     # setup sip
     li t0, 32
     csrw sip, t0
+    j 2f
+
+1:
+    # clear msip of this hart and set sip.SSIP
+    csrr t0, mhartid
+    slli t0, t0, 2
+    li t1, 0x2000000
+    add t0, t0, t1
+    sw zero, 0(t0)
+    csrs mip, 2
 
+2:
     ld t0, 0(sp)
     ld t1, 1*8(sp)
     ld t2, 2*8(sp)
@@ -6,6 +6,10 @@ pub struct TrapContext {
     pub kernel_satp: usize,
     pub trap_handler: usize,
     pub kernel_sp: usize,
+    // hart the task returned to user mode on, restored into tp on the next trap
+    pub hart_id: usize,
+    // nonzero if the TLB is flushed when switching between user and kernel space
+    pub flush_tlb: usize,
 }
 
 impl TrapContext {
@@ -26,6 +30,8 @@ impl TrapContext {
             kernel_satp,
             trap_handler,
             kernel_sp,
+            hart_id: 0,
+            flush_tlb: 0,
         }
     }
 }
@@ -1,8 +1,9 @@
 use core::arch::{asm, global_asm};
 
 use riscv::register::{scause::{self, Exception, Interrupt, Trap}, stval, stvec, utvec::TrapMode};
-use crate::{config::{TRAMPOLINE_ADDR, TRAP_CONTEXT}, println, syscall::{id::SYSCALL_EXIT, syscall}, task::{processor::{current_trap_cx, current_user_satp}, show_task_frames, suspend_current_and_run_next}, time::{get_mtime_cmp, get_time}};
+use crate::{config::TRAMPOLINE_ADDR, mm::{address::VirtAddr, address_space::Access, asid::has_asids, page_table::leave_user}, println, syscall::{exit_group, syscall}, task::{current_killed, exit_current_thread, processor::{current_task, current_trap_cx, current_user_satp, hart_id}, preempt_current_and_run_next, show_task_frames}, time::{check_timers, get_mtime_cmp, get_time}};
 pub mod context;
+use self::context::TrapContext;
 
 global_asm!(include_str!("trap.S"));
 
@@ -16,40 +17,98 @@ pub fn set_user_stvec() {
 
 #[no_mangle]
 pub fn trap_handler() -> ! {
+    // harts revoking mappings of this space stop waiting for this one
+    leave_user(hart_id());
     set_kernel_stvec();
     let scause = scause::read();
     match scause.cause() {
         Trap::Exception(Exception::UserEnvCall) => {
             let cx = current_trap_cx();
             cx.sepc += 4;
-            // syscall id in a0, args in a1-a4
-            let result = syscall(cx.x[10], [cx.x[11], cx.x[12], cx.x[13], cx.x[14]]) as usize;
+            // syscall id in a0, args in a1-a6
+            let result = syscall(cx.x[10], [cx.x[11], cx.x[12], cx.x[13], cx.x[14], cx.x[15], cx.x[16]]) as usize;
             let cx = current_trap_cx();
             cx.x[10] = result;
             if get_time() > get_mtime_cmp() {
-                suspend_current_and_run_next();
+                preempt_current_and_run_next();
             }
         }
+        Trap::Exception(Exception::StorePageFault) if handle_page_fault(stval::read(), Access::Store) => {
+            // retry the store on the now present and private page
+        }
+        Trap::Exception(Exception::LoadPageFault) if handle_page_fault(stval::read(), Access::Load) => {
+            // retry the load on the now present page
+        }
+        Trap::Exception(Exception::InstructionPageFault) if handle_page_fault(stval::read(), Access::Fetch) => {
+            // retry the fetch on the now present page
+        }
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
             let cx = current_trap_cx();
             cx.sepc += 4;
-            suspend_current_and_run_next();
+            check_timers();
+            preempt_current_and_run_next();
+        }
+        Trap::Interrupt(Interrupt::SupervisorSoft) => {
+            // another hart revoked a mapping, the flush happens when the space is activated again
+            unsafe { asm!("csrc sip, {}", in(reg) 2) };
+        }
+        Trap::Exception(Exception::StorePageFault | Exception::LoadPageFault) if is_stack_overflow(stval::read()) => {
+            println!(
+                "[kernel] Process killed because of stack overflow at {:#x}",
+                stval::read(),
+            );
+            exit_group(-1);
         }
         _ => {
             println!(
                 "[kernel] Process killed because of {:?}",
                 scause.cause(),
             );
-            syscall(SYSCALL_EXIT, [(-1isize) as usize, 0, 0, 0]);
+            exit_group(-1);
         }
     }
     trap_return();
 }
 
+// resolve a page fault of current task on a lazy or copy-on-write page
+// growing the stack first if addr is just below it
+fn handle_page_fault(addr: usize, access: Access) -> bool {
+    let task = current_task().unwrap();
+    let inner = task.inner.lock();
+    let sp = inner.trap_cx_ppn.get_mut::<TrapContext>().x[2];
+    let mut space = inner.user_space.lock();
+    space.grow_stack(addr, sp);
+    space.handle_page_fault(VirtAddr(addr).floor(), access)
+}
+
+fn is_stack_overflow(addr: usize) -> bool {
+    let task = current_task().unwrap();
+    let user_space = task.user_space();
+    let space = user_space.lock();
+    space.is_stack_overflow(addr)
+}
+
 #[no_mangle]
 pub fn trap_return() -> ! {
+    // a thread killed as its process exits never gets back to user mode
+    // a killed main thread exits the process after a fatal trap in another thread
+    if current_killed() {
+        let task = current_task().unwrap();
+        if task.is_main_thread() {
+            let exit_code = task.inner.lock().kill_code;
+            drop(task);
+            exit_group(exit_code);
+        }
+        drop(task);
+        exit_current_thread(-1);
+    }
     set_user_stvec();
-    let trap_cx_ptr = TRAP_CONTEXT;
+    let trap_cx_ptr = current_task().unwrap().trap_cx_va();
+    current_trap_cx().hart_id = hart_id();
+    // user and kernel spaces share ASID 0 on harts without ASIDs
+    let flush_tlb = !has_asids() as usize;
+    current_trap_cx().flush_tlb = flush_tlb;
+    // last, nothing may wait for a lock once the hart counts as running the space
     let user_satp = current_user_satp();
     let restore_va = __restore as usize - __alltraps as usize + TRAMPOLINE_ADDR;
     extern "C" {
@@ -63,6 +122,7 @@ pub fn trap_return() -> ! {
             va = in(reg) restore_va,
             in("a0") trap_cx_ptr,
             in("a1") user_satp,
+            in("a2") flush_tlb,
             options(noreturn)
         );
     }
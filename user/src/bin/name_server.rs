@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+
+extern crate user_lib;
+
+use user_lib::{name::*, syscall::*};
+
+// handle of name_server's own endpoint
+const NAME_SERVER_RECV: usize = 0;
+const MAX_SERVICES: usize = 16;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    name: [usize; NAME_MSG_LEN - 2],
+    len: usize,
+    // handle to send to the service
+    handle: usize,
+}
+
+#[no_mangle]
+fn main() -> i32 {
+    let mut entries: [Option<Entry>; MAX_SERVICES] = [None; MAX_SERVICES];
+    let mut buffer = [0usize; NAME_MSG_LEN];
+    loop {
+        let (ret, mut cap, token) = recv(NAME_SERVER_RECV, buffer.as_mut_ptr(), NAME_MSG_LEN);
+        // nothing was received, buffer holds the last request
+        if ret < 0 {
+            continue;
+        }
+        let mut name = [0usize; NAME_MSG_LEN - 2];
+        name.copy_from_slice(&buffer[2..]);
+        let len = buffer[1];
+        let found = entries
+            .iter()
+            .flatten()
+            .find(|e| e.len == len && e.name == name)
+            .map(|e| e.handle);
+        let (status, reply_cap) = match buffer[0] {
+            NAME_REGISTER if cap != NO_CAP && found.is_none() => {
+                if let Some(slot) = entries.iter_mut().find(|e| e.is_none()) {
+                    *slot = Some(Entry { name, len, handle: cap });
+                    cap = NO_CAP;
+                    (0, NO_CAP)
+                } else {
+                    (-1isize as usize, NO_CAP)
+                }
+            },
+            NAME_LOOKUP if found.is_some() => (0, found.unwrap()),
+            _ => (-1isize as usize, NO_CAP),
+        };
+        // a handle that was not stored is not needed
+        if cap != NO_CAP {
+            cap_drop(cap);
+        }
+        buffer[0] = status;
+        if token != NO_CAP {
+            reply(token, buffer.as_ptr(), 1, reply_cap);
+        }
+    }
+}
@@ -1,48 +1,48 @@
 #![no_std]
 #![no_main]
 
-use allocator::buddy_allocator::BuddyAllocator;
 extern crate user_lib;
 
 use user_lib::syscall::*;
 
-const HEAP_SIZE: usize = 0x80_000;
-const HEAP_UNIT: usize = 6;
-
-#[link_section = ".data.heap"]
-static mut HEAP_SPACE: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
-
-#[global_allocator]
-static mut HEAP_ALLOCATOR: BuddyAllocator = BuddyAllocator::empty(HEAP_UNIT);
-
 #[no_mangle]
 fn main() -> i32 {
-    unsafe {
-        let heap_begin = HEAP_SPACE.as_ptr() as usize;
-        HEAP_ALLOCATOR
-            .inner.lock()
-            .add_space(heap_begin, heap_begin + HEAP_SIZE);
-    }
-    let mut process_manager = ProcessManager::new(2);
+    // init is created right after process_manager and name_server
+    let mut process_manager = ProcessManager::new(3);
+    // waitpid requests held until a child exits: (parent, pid, reply token)
+    let mut waiting: Vec<(usize, isize, usize)> = Vec::new();
     let mut buffer = [0usize; 3];
-    recv(buffer.as_mut_ptr(), 3);
     loop {
+        let (ret, _, token) = recv(PROCESS_MANAGER_EP, buffer.as_mut_ptr(), 3);
+        // nothing was received, buffer holds the last request
+        if ret < 0 {
+            continue;
+        }
         match buffer[0] {
             SYSCALL_FORK => {
                 process_manager.fork(buffer[1], buffer[2]);
-                recv(buffer.as_mut_ptr(), 3);
+                reply(token, buffer.as_ptr(), 0, NO_CAP);
             },
             SYSCALL_EXIT => {
                 process_manager.exit(buffer[1], buffer[2] as i32);
-                recv(buffer.as_mut_ptr(), 3);
+                if token != NO_CAP {
+                    reply(token, buffer.as_ptr(), 0, NO_CAP);
+                }
+                // the exited task or its orphans may satisfy held requests
+                waiting.retain(|&(parent, pid, token)| {
+                    !process_manager.answer_waitpid(parent, pid, token)
+                });
             },
             SYSCALL_WAITPID => {
-                let (pid, exitcode) = process_manager.waitpid(buffer[1], buffer[2] as isize);
-                buffer[0] = pid as usize;
-                buffer[1] = exitcode as usize;
-                sendrecv(buffer.as_ptr(), 2, buffer.as_mut_ptr(), 3);
+                if !process_manager.answer_waitpid(buffer[1], buffer[2] as isize, token) {
+                    waiting.push((buffer[1], buffer[2] as isize, token));
+                }
+            },
+            _ => {
+                if token != NO_CAP {
+                    reply(token, buffer.as_ptr(), 0, NO_CAP);
+                }
             },
-            _ => {recv(buffer.as_mut_ptr(), 3);},
         }
     }
 }
@@ -147,5 +147,16 @@ impl ProcessManager {
         }
     }
 
+    // reply to a waitpid request unless no child has exited yet
+    pub fn answer_waitpid(&mut self, parentid: usize, pid: isize, token: usize) -> bool {
+        let (pid, exit_code) = self.waitpid(parentid, pid);
+        if pid == -2 {
+            return false;
+        }
+        let answer = [pid as usize, exit_code as usize];
+        reply(token, answer.as_ptr(), 2, NO_CAP);
+        true
+    }
+
 }
 
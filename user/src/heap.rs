@@ -0,0 +1,31 @@
+// global allocator growing the heap with sbrk
+use core::{alloc::Layout, cmp::max};
+use allocator::buddy_allocator::{BuddyAllocator, MAX_BLOCK_SIZE};
+use crate::syscall::sbrk;
+
+const HEAP_UNIT: usize = 6;
+// grow the heap by at least this many bytes at a time
+const HEAP_INCREMENT: usize = 0x4000;
+
+// out-of-memory handler asking the kernel for more heap pages
+fn grow_heap(layout: &Layout) -> bool {
+    // no space could ever serve a block this large, and the increment below would overflow
+    if max(layout.size(), layout.align()) > MAX_BLOCK_SIZE {
+        return false;
+    }
+    let increment = {
+        let inner = unsafe { HEAP.inner.lock() };
+        // twice the block size always holds an aligned block, the rest leaves room for the bitmap
+        // doubling the heap keeps the number of spaces added to the allocator small
+        max(max(4 * inner.block_size(layout), HEAP_INCREMENT), inner.space())
+    };
+    let start = sbrk(increment as isize);
+    if start < 0 {
+        return false;
+    }
+    let start = start as usize;
+    unsafe { HEAP.add_space(start, start + increment) }
+}
+
+#[global_allocator]
+static mut HEAP: BuddyAllocator = BuddyAllocator::with_oom_handler(HEAP_UNIT, Some(grow_heap));
@@ -6,6 +6,11 @@
 pub mod console;
 mod lang_items;
 pub mod syscall;
+pub mod name;
+#[cfg(feature = "heap")]
+mod heap;
+
+extern crate alloc;
 
 #[no_mangle]
 #[link_section = ".text.entry"]
@@ -35,31 +40,37 @@ pub fn yield_() -> isize {
     sys_yield()
 }
 
+// block until a child exits, return -1 if there is no such child
 pub fn wait(exit_code: &mut i32) -> isize {
-    loop {
-        match sys_waitpid(-1, exit_code as *mut _) {
-            -2 => {
-                yield_();
-            }
-            exit_pid => return exit_pid,
-        }
-    }
+    sys_waitpid(-1, exit_code as *mut _)
 }
 
 pub fn waitpid(pid: isize, exit_code: &mut i32) -> isize {
-    loop {
-        match sys_waitpid(pid, exit_code as *mut _) {
-            -2 => {
-                yield_();
-            }
-            exit_pid => return exit_pid,
-        }
-    }
+    sys_waitpid(pid, exit_code as *mut _)
+}
+
+// run f(arg) in a new thread of this process, it exits with the return value of f
+// return the tid of the thread, -1 if it cannot be created
+pub fn thread_create(f: fn(usize) -> i32, arg: usize) -> isize {
+    sys_thread_create(thread_start as usize, f as usize, arg)
+}
+
+extern "C" fn thread_start(f: usize, arg: usize) -> ! {
+    let f: fn(usize) -> i32 = unsafe { core::mem::transmute(f) };
+    thread_exit(f(arg));
+}
+
+pub fn thread_exit(exit_code: i32) -> ! {
+    sys_thread_exit(exit_code);
+    panic!("unreachable after sys_thread_exit!");
+}
+
+// block until thread tid of this process exits
+// return tid, -1 if there is no such thread or another thread joins it
+pub fn thread_join(tid: usize, exit_code: &mut i32) -> isize {
+    sys_thread_join(tid, exit_code as *mut _)
 }
 
 pub fn sleep(time_ms: usize) {
-    let start = get_time();
-    while get_time() < start + time_ms as isize {
-        sys_yield();
-    }
+    sys_sleep(time_ms);
 }
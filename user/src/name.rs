@@ -0,0 +1,60 @@
+// client side of name_server, shared protocol constants
+use crate::syscall::*;
+
+// handle of name_server's endpoint in every task started from init
+pub const NAME_SERVER_EP: usize = 0;
+
+pub const NAME_REGISTER: usize = 1;
+pub const NAME_LOOKUP: usize = 2;
+
+// longest service name in bytes
+pub const NAME_MAX: usize = 32;
+// request: [op, name length, name bytes packed in words]
+pub const NAME_MSG_LEN: usize = 2 + NAME_MAX / 8;
+
+fn pack_request(op: usize, name: &str) -> Option<[usize; NAME_MSG_LEN]> {
+    let bytes = name.as_bytes();
+    if bytes.is_empty() || bytes.len() > NAME_MAX {
+        return None;
+    }
+    let mut msg = [0usize; NAME_MSG_LEN];
+    msg[0] = op;
+    msg[1] = bytes.len();
+    for (i, byte) in bytes.iter().enumerate() {
+        msg[2 + i / 8] |= (*byte as usize) << (i % 8 * 8);
+    }
+    Some(msg)
+}
+
+// register endpoint ep under name, others can only send to it
+// return -1 if name is invalid or already taken
+pub fn register_service(name: &str, ep: usize) -> isize {
+    let msg = match pack_request(NAME_REGISTER, name) {
+        Some(msg) => msg,
+        None => return -1,
+    };
+    let send = cap_dup(ep, CAP_SEND | CAP_GRANT);
+    if send < 0 {
+        return -1;
+    }
+    let mut reply = [0usize; 1];
+    let (len, _) = call(NAME_SERVER_EP, msg.as_ptr(), NAME_MSG_LEN, reply.as_mut_ptr(), 1, send as usize);
+    cap_drop(send as usize);
+    // a failed call or a short reply leaves reply[0] at 0, which would read as success
+    if len != 1 {
+        return -1;
+    }
+    reply[0] as isize
+}
+
+// return a handle to send to the service registered under name
+pub fn lookup_service(name: &str) -> Option<usize> {
+    let msg = pack_request(NAME_LOOKUP, name)?;
+    let mut reply = [0usize; 1];
+    let (len, cap) = call(NAME_SERVER_EP, msg.as_ptr(), NAME_MSG_LEN, reply.as_mut_ptr(), 1, NO_CAP);
+    if len != 1 || reply[0] != 0 || cap == NO_CAP {
+        None
+    } else {
+        Some(cap)
+    }
+}
@@ -2,13 +2,67 @@ pub const SYSCALL_WRITE: usize = 1;
 pub const SYSCALL_EXIT: usize = 2;
 pub const SYSCALL_YIELD: usize = 3;
 pub const SYSCALL_RECV: usize = 4;
-pub const SYSCALL_SENDRECV: usize = 5;
+pub const SYSCALL_REPLY: usize = 5;
 pub const SYSCALL_FORK: usize = 6;
 pub const SYSCALL_EXEC: usize = 7;
 pub const SYSCALL_WAITPID: usize = 8;
 pub const SYSCALL_READ: usize = 9;
 pub const SYSCALL_GETPID: usize = 10;
 pub const SYSCALL_GETTIME: usize = 11;
+pub const SYSCALL_CALL: usize = 12;
+pub const SYSCALL_ENDPOINT_CREATE: usize = 13;
+pub const SYSCALL_SEND: usize = 14;
+pub const SYSCALL_TRY_RECV: usize = 15;
+pub const SYSCALL_CAP_DUP: usize = 16;
+pub const SYSCALL_CAP_DROP: usize = 17;
+pub const SYSCALL_CAP_TASK: usize = 18;
+pub const SYSCALL_CAP_MEMORY: usize = 19;
+pub const SYSCALL_CAP_IDENTIFY: usize = 20;
+pub const SYSCALL_MEM_MAP: usize = 21;
+pub const SYSCALL_MEM_UNMAP: usize = 22;
+pub const SYSCALL_IPC_TIMEOUT: usize = 23;
+pub const SYSCALL_MMAP: usize = 24;
+pub const SYSCALL_MUNMAP: usize = 25;
+pub const SYSCALL_MPROTECT: usize = 26;
+pub const SYSCALL_BRK: usize = 27;
+pub const SYSCALL_GET_PRIORITY: usize = 28;
+pub const SYSCALL_SET_PRIORITY: usize = 29;
+pub const SYSCALL_THREAD_CREATE: usize = 30;
+pub const SYSCALL_THREAD_EXIT: usize = 31;
+pub const SYSCALL_THREAD_JOIN: usize = 32;
+pub const SYSCALL_GETTID: usize = 33;
+pub const SYSCALL_SLEEP: usize = 34;
+
+// handle value meaning "no capability"
+pub const NO_CAP: usize = usize::MAX;
+
+// returned by any syscall given a bad pointer
+pub const EFAULT: isize = -14;
+
+// errors of ipc syscalls
+pub const IPC_QUEUE_FULL: isize = -2;
+pub const IPC_TIMEOUT: isize = -3;
+pub const IPC_CLOSED: isize = -4;
+pub const IPC_TOO_LONG: isize = -6;
+// longest message call, send and reply accept
+pub const IPC_MAX_WORDS: usize = 512;
+
+// range of nice values, lower runs more
+pub const NICE_MIN: isize = -20;
+pub const NICE_MAX: isize = 19;
+
+// memory protection of mmap and mprotect
+pub const PROT_READ: usize = 1 << 0;
+pub const PROT_WRITE: usize = 1 << 1;
+pub const PROT_EXEC: usize = 1 << 2;
+
+// capability rights
+pub const CAP_SEND: usize = 1 << 0;
+pub const CAP_RECV: usize = 1 << 1;
+pub const CAP_GRANT: usize = 1 << 2;
+
+// handle of process_manager's endpoint in its own table
+pub const PROCESS_MANAGER_EP: usize = 0;
 
 use core::arch::asm;
 
@@ -27,10 +81,32 @@ fn syscall(id: usize, args: [usize; 4]) -> isize {
     ret
 }
 
+// ipc syscalls also return the handle of a received capability in a1
+// and the handle of a reply token in a2
+fn syscall_ipc(id: usize, args: [usize; 6]) -> (isize, usize, usize) {
+    let mut ret: isize;
+    let mut cap: usize;
+    let mut token: usize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") id => ret,
+            inlateout("x11") args[0] => cap,
+            inlateout("x12") args[1] => token,
+            in("x13") args[2],
+            in("x14") args[3],
+            in("x15") args[4],
+            in("x16") args[5],
+        );
+    }
+    (ret, cap, token)
+}
+
 pub fn sys_write(fd: usize, buffer: &[u8]) -> isize {
     syscall(SYSCALL_WRITE, [fd, buffer.as_ptr() as usize, buffer.len(), 0])
 }
 
+// ends every thread of the process
 pub fn sys_exit(exit_code: i32) -> isize {
     syscall(SYSCALL_EXIT, [exit_code as usize, 0, 0, 0])
 }
@@ -39,12 +115,103 @@ pub fn sys_yield() -> isize {
     syscall(SYSCALL_YIELD, [0, 0, 0, 0])
 }
 
-pub fn recv(ptr: *mut usize, len: usize) -> isize {
-    syscall(SYSCALL_RECV, [ptr as usize, len, 0, 0])
+// receiving calls return (sender's pid, handle of received capability or NO_CAP,
+// reply token of the caller or NO_CAP for asynchronous messages)
+pub fn recv(ep: usize, ptr: *mut usize, len: usize) -> (isize, usize, usize) {
+    syscall_ipc(SYSCALL_RECV, [ep, ptr as usize, len, 0, 0, 0])
 }
 
-pub fn sendrecv(send: *const usize, send_len: usize, recv: *mut usize, recv_len: usize) -> isize {
-    syscall(SYSCALL_SENDRECV, [send as usize, send_len, recv as usize, recv_len])
+// return -2 if no message is queued
+pub fn try_recv(ep: usize, ptr: *mut usize, len: usize) -> (isize, usize, usize) {
+    syscall_ipc(SYSCALL_TRY_RECV, [ep, ptr as usize, len, 0, 0, 0])
+}
+
+// answer the caller of token, which can be done after serving other requests
+// the token is used up, return IPC_CLOSED if the caller gave up
+pub fn reply(token: usize, ptr: *const usize, len: usize, cap: usize) -> isize {
+    syscall(SYSCALL_REPLY, [token, ptr as usize, len, cap])
+}
+
+// return -2 if the queue of ep is full
+pub fn send_async(ep: usize, ptr: *const usize, len: usize, cap: usize) -> isize {
+    syscall(SYSCALL_SEND, [ep, ptr as usize, len, cap])
+}
+
+// return (length of reply, handle of received capability or NO_CAP)
+pub fn call(ep: usize, send: *const usize, send_len: usize, recv: *mut usize, recv_len: usize, cap: usize) -> (isize, usize) {
+    let (len, cap, _) = syscall_ipc(SYSCALL_CALL, [ep, send as usize, send_len, recv as usize, recv_len, cap]);
+    (len, cap)
+}
+
+// later call and recv fail with IPC_TIMEOUT after ms, 0 waits forever
+pub fn ipc_timeout(ms: usize) -> isize {
+    syscall(SYSCALL_IPC_TIMEOUT, [ms, 0, 0, 0])
+}
+
+pub fn endpoint_create() -> isize {
+    syscall(SYSCALL_ENDPOINT_CREATE, [0, 0, 0, 0])
+}
+
+pub fn cap_dup(handle: usize, rights: usize) -> isize {
+    syscall(SYSCALL_CAP_DUP, [handle, rights, 0, 0])
+}
+
+pub fn cap_drop(handle: usize) -> isize {
+    syscall(SYSCALL_CAP_DROP, [handle, 0, 0, 0])
+}
+
+pub fn cap_task() -> isize {
+    syscall(SYSCALL_CAP_TASK, [0, 0, 0, 0])
+}
+
+// share the pages of [start, start + len) without copying, pass the handle over ipc
+pub fn cap_memory(start: usize, len: usize) -> isize {
+    syscall(SYSCALL_CAP_MEMORY, [start, len, 0, 0])
+}
+
+pub fn cap_identify(handle: usize) -> isize {
+    syscall(SYSCALL_CAP_IDENTIFY, [handle, 0, 0, 0])
+}
+
+// map the pages of a memory handle at page-aligned addr
+pub fn mem_map(handle: usize, addr: usize) -> isize {
+    syscall(SYSCALL_MEM_MAP, [handle, addr, 0, 0])
+}
+
+pub fn mem_unmap(addr: usize) -> isize {
+    syscall(SYSCALL_MEM_UNMAP, [addr, 0, 0, 0])
+}
+
+// map len bytes of zeroed memory at page-aligned addr, or anywhere if addr is 0
+// return the start address or -1
+pub fn mmap(addr: usize, len: usize, prot: usize) -> isize {
+    syscall(SYSCALL_MMAP, [addr, len, prot, 0])
+}
+
+pub fn munmap(addr: usize, len: usize) -> isize {
+    syscall(SYSCALL_MUNMAP, [addr, len, 0, 0])
+}
+
+pub fn mprotect(addr: usize, len: usize, prot: usize) -> isize {
+    syscall(SYSCALL_MPROTECT, [addr, len, prot, 0])
+}
+
+// move the end of the heap to addr, 0 only queries it
+// return the new end or -1
+pub fn brk(addr: usize) -> isize {
+    syscall(SYSCALL_BRK, [addr, 0, 0, 0])
+}
+
+// grow or shrink the heap by increment bytes, return the old end or -1
+pub fn sbrk(increment: isize) -> isize {
+    let old = brk(0);
+    if increment == 0 || old < 0 {
+        return old;
+    }
+    if brk((old + increment) as usize) < 0 {
+        return -1;
+    }
+    old
 }
 
 pub fn fork() -> isize {
@@ -70,3 +237,42 @@ pub fn getpid() -> isize {
 pub fn get_time() -> isize {
     syscall(SYSCALL_GETTIME, [0, 0, 0, 0])
 }
+
+// nice value of task pid, 0 for the current task
+pub fn get_priority(pid: usize) -> Option<isize> {
+    let ret = syscall(SYSCALL_GET_PRIORITY, [pid, 0, 0, 0]);
+    if ret < 0 {
+        None
+    } else {
+        Some(20 - ret)
+    }
+}
+
+// only services and the parent may lower nice or change other tasks
+pub fn set_priority(pid: usize, nice: isize) -> isize {
+    syscall(SYSCALL_SET_PRIORITY, [pid, nice as usize, 0, 0])
+}
+
+// start a thread at entry with arg0 in a0 and arg1 in a1, return its tid or -1
+// threads share handles, a handle one thread receives or drops is seen by all
+pub fn sys_thread_create(entry: usize, arg0: usize, arg1: usize) -> isize {
+    syscall(SYSCALL_THREAD_CREATE, [entry, arg0, arg1, 0])
+}
+
+// exits the whole process if called by the main thread
+pub fn sys_thread_exit(exit_code: i32) -> isize {
+    syscall(SYSCALL_THREAD_EXIT, [exit_code as usize, 0, 0, 0])
+}
+
+pub fn sys_thread_join(tid: usize, exit_code: *mut i32) -> isize {
+    syscall(SYSCALL_THREAD_JOIN, [tid, exit_code as usize, 0, 0])
+}
+
+pub fn gettid() -> isize {
+    syscall(SYSCALL_GETTID, [0, 0, 0, 0])
+}
+
+// block for at least time_ms milliseconds
+pub fn sys_sleep(time_ms: usize) -> isize {
+    syscall(SYSCALL_SLEEP, [time_ms, 0, 0, 0])
+}